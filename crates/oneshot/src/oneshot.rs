@@ -6,6 +6,8 @@ use crate::ReceiveError;
 
 pub struct OneshotChannelSender<T> {
     data: Arc<UnsafeCell<Option<T>>>,
+    /// The flag is set once the sender is gone, either because it sent the data or because it
+    /// was dropped. It lets a waiting receiver know that no data will ever arrive.
     sync_pair: Arc<(Mutex<bool>, Condvar)>,
 }
 
@@ -26,50 +28,56 @@ impl<T> OneshotChannelSender<T> {
     /// The sender is consumed so the resources are released
     /// and no other thread can send data.
     pub fn send(self, data: T) -> Result<(), SendError> {
-        let (mutex, condvar) = &*self.sync_pair;
-        let __ = mutex.lock().unwrap();
-
-        match Arc::try_unwrap(self.data) {
-            Ok(_) => Err(SendError::Closed),
-            Err(shared_data) => {
-                // SAFETY: when this block is reached, we have exclusive access
-                // over the shared mutex.
-                unsafe {
-                    *shared_data.get() = Some(data);
-                }
-                condvar.notify_one();
-
-                Ok(())
-            }
+        let (mutex, _) = &*self.sync_pair;
+        let _guard = mutex.lock().unwrap();
+
+        // The receiver was dropped, nobody will read the data
+        if Arc::strong_count(&self.data) == 1 {
+            return Err(SendError::Closed);
+        }
+
+        // SAFETY: when this block is reached, we have exclusive access
+        // over the shared mutex.
+        unsafe {
+            *self.data.get() = Some(data);
         }
+        // The receiver is notified when the sender is dropped right after this
+        Ok(())
+    }
+}
+
+impl<T> Drop for OneshotChannelSender<T> {
+    fn drop(&mut self) {
+        let (mutex, condvar) = &*self.sync_pair;
+        let mut is_sender_gone = mutex.lock().unwrap_or_else(|err| err.into_inner());
+        *is_sender_gone = true;
+        condvar.notify_one();
     }
 }
 
 impl<T> OneshotChannelReceiver<T> {
     /// Blocks until ther is data available.
     /// The data is made available by the sender when send is called.
+    /// Fails with Closed if the sender was dropped without sending anything.
     pub fn try_recv(self) -> Result<T, ReceiveError> {
         let (mutex, condvar) = &*self.sync_pair;
-        let mut guard = mutex
+        let mut is_sender_gone = mutex
             .lock()
             .map_err(|err| ReceiveError::Other(err.to_string()))?;
 
-        match Arc::try_unwrap(self.data) {
-            Ok(_) => Err(ReceiveError::Closed),
-            Err(shared_data) => {
-                // SAFETY: when this block is reached, we have exclusive access
-                // over the shared mutex.
-                unsafe {
-                    let data = &mut *shared_data.get();
-
-                    while data.is_none() {
-                        guard = condvar
-                            .wait(guard)
-                            .map_err(|err| ReceiveError::Other(err.to_string()))?;
-                    }
-                    Ok(data.take().expect("msg"))
-                }
+        loop {
+            // SAFETY: when this block is reached, we have exclusive access
+            // over the shared mutex.
+            let data = unsafe { (*self.data.get()).take() };
+            if let Some(data) = data {
+                return Ok(data);
             }
+            if *is_sender_gone {
+                return Err(ReceiveError::Closed);
+            }
+            is_sender_gone = condvar
+                .wait(is_sender_gone)
+                .map_err(|err| ReceiveError::Other(err.to_string()))?;
         }
     }
 
@@ -95,14 +103,13 @@ impl<T> OneshotChannelReceiver<T> {
 ///     tx.send(69).unwrap();
 /// });
 ///
-/// let data = match rx.recv() {
+/// let data = match rx.try_recv() {
 ///     Ok(num) => num,
 ///     Err(_) => unreachable!(),
 /// };
 ///
 /// assert_eq!(data, 69);
 /// ```
-
 pub fn channel<T>() -> (OneshotChannelSender<T>, OneshotChannelReceiver<T>) {
     let data1 = Arc::new(UnsafeCell::new(None));
     let data2 = data1.clone();
@@ -136,7 +143,7 @@ mod test {
             tx.send(69).unwrap();
         });
 
-        let data = match rx.recv() {
+        let data = match rx.try_recv() {
             Ok(num) => num,
             Err(_) => unreachable!(),
         };
@@ -149,7 +156,7 @@ mod test {
         let (tx, rx) = channel::<u64>();
 
         thread::spawn(move || {
-            let data = match rx.recv() {
+            let data = match rx.try_recv() {
                 Ok(num) => num,
                 Err(_) => unreachable!(),
             };
@@ -160,6 +167,14 @@ mod test {
         tx.send(69).unwrap();
     }
 
+    #[test]
+    fn test_oneshot_send_before_receive() {
+        let (tx, rx) = channel::<u64>();
+
+        tx.send(69).unwrap();
+        assert_eq!(rx.try_recv(), Ok(69));
+    }
+
     #[test]
     fn test_oneshot_handle_receiver_drop() {
         let (tx, rx) = channel::<u64>();
@@ -173,7 +188,7 @@ mod test {
         let (tx, rx) = channel::<u64>();
 
         drop(tx);
-        assert_eq!(rx.recv().unwrap_err(), ReceiveError::Closed);
+        assert_eq!(rx.try_recv().unwrap_err(), ReceiveError::Closed);
     }
 
     #[test]
@@ -1,5 +1,7 @@
+#[allow(unused)]
 pub struct Schema {}
 
+#[allow(unused)]
 pub enum Value {
     Int(i32),
     Float(f32),
@@ -1,10 +1,17 @@
 use std::sync::LazyLock;
+use std::time::Duration;
 
 use crate::macros::static_assert;
 
-/// The size (in bytes) of a page in the buffer pool
+/// The size (in bytes) of a page in the buffer pool, unless the database was created with
+/// another one
 pub const PAGE_SIZE: usize = 4096;
 
+/// The smallest and largest page sizes a database can be created with. Page sizes are powers of
+/// two, and offsets within a page must fit in 16 bits.
+pub const MIN_PAGE_SIZE: usize = 4096;
+pub const MAX_PAGE_SIZE: usize = 32768;
+
 pub static CARGO_PKG_NAME: LazyLock<String> =
     LazyLock::new(|| std::env::var("CARGO_PKG_NAME").unwrap_or("maridbel".into()));
 
@@ -15,4 +22,35 @@ pub const BUFFER_POOL_N_FRAMES: usize = 69;
 
 pub const LRU_K: usize = 4;
 
-static_assert!(PAGE_SIZE % 8 == 0);
+/// Data files grow by this many pages at a time
+pub const DATA_FILE_EXTENT_PAGES: u64 = 64;
+
+/// How many threads the disk scheduler uses to serve IO requests by default
+pub const DISK_SCHEDULER_N_WORKERS: usize = 4;
+
+/// How many IO requests each disk worker keeps in flight when using io_uring
+#[cfg(feature = "io_uring")]
+pub const IO_URING_QUEUE_DEPTH: u32 = 64;
+
+/// How many requests for adjacent pages the disk scheduler merges into a single IO operation
+pub const IO_MAX_COALESCED_PAGES: usize = 32;
+
+/// How long an IO request waits in the disk scheduler queue before it is promoted to the next
+/// priority class
+pub const IO_PRIORITY_AGING: Duration = Duration::from_millis(50);
+
+/// How many times the disk scheduler retries an IO operation that failed with a transient error
+pub const IO_MAX_RETRIES: u32 = 3;
+
+/// How long the disk scheduler waits before retrying a failed IO operation the first time
+pub const IO_RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
+/// In debug builds, a page pinned for longer than this is reported as a possible pin leak.
+pub const PIN_LEAK_THRESHOLD: Duration = Duration::from_secs(30);
+
+static_assert!(
+    PAGE_SIZE.is_multiple_of(8)
+        && PAGE_SIZE >= MIN_PAGE_SIZE
+        && PAGE_SIZE <= MAX_PAGE_SIZE
+        && MAX_PAGE_SIZE <= u16::MAX as usize
+);
@@ -1,55 +1,514 @@
-use std::fs;
-use std::io::{Read, Seek, Write};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::config::BUFFER_POOL_N_FRAMES;
-use crate::storage::BufferPool;
+use crate::config::{
+    BUFFER_POOL_N_FRAMES, DATA_FILE_EXTENT_PAGES, DISK_SCHEDULER_N_WORKERS, MAX_PAGE_SIZE,
+    MIN_PAGE_SIZE, PAGE_SIZE,
+};
+use crate::errors::{BufferPoolError, DatabaseError, PageError, TablespaceError};
+use crate::storage::buffer::compaction::compact;
+use crate::storage::buffer::frame::PageWriteGuard;
+use crate::storage::buffer::snapshot::BackupProgress;
+use crate::storage::disk::disk_scheduler::{DiskScheduler, RetryPolicy};
+use crate::storage::disk::{double_write_path, DataFileOptions, OpenMode, StorageKind};
+use crate::storage::page::{HeaderPage, TablespaceEntry, DATABASE_FORMAT_VERSION, INVALID_PAGE_ID};
+use crate::storage::{
+    BufferPool, DiskManager, Durability, EncryptionKey, FileId, MemoryBackend, PageId, PageType,
+    StorageBackend,
+};
 
 pub struct Database {
-    /// The filename of the database file. None if the database is in memory.
-    filename: Option<String>,
+    /// The path of the database file. None if the database is in memory.
+    path: Option<PathBuf>,
+    /// Opened with `open_read_only`, nothing can be modified
+    read_only: bool,
     buffer_pool: Arc<BufferPool>,
 }
 
 impl Database {
-    pub fn from_buffer<R>(reader: R, config: DatabaseConfig) -> Self
+    /// Opens the database stored in the given backend. With an encryption key, the pages of the
+    /// backend must be `ENCRYPTION_TRAILER_SIZE` bytes larger than the pages of the database.
+    pub fn from_backend<B>(backend: B, config: DatabaseConfig) -> Self
     where
-        R: Read + Write + Seek + Send + 'static,
+        B: StorageBackend + 'static,
     {
+        let file_options = config.file_options();
+        Database::with_backend(backend, file_options, config)
+    }
+
+    fn with_backend<B>(backend: B, file_options: DataFileOptions, config: DatabaseConfig) -> Self
+    where
+        B: StorageBackend + 'static,
+    {
+        let mut disk_manager = DiskManager::new(file_options.encrypt(backend, 0));
+        disk_manager.set_file_options(file_options);
+        Database::with_disk_manager(disk_manager, config)
+    }
+
+    fn with_disk_manager(mut disk_manager: DiskManager, config: DatabaseConfig) -> Self {
+        disk_manager.set_extent_pages(config.extent_pages);
+        disk_manager.set_durability(config.durability);
+        log::debug!("Opening database at {:?}", disk_manager.path());
+        let path = disk_manager.path();
+        let read_only = disk_manager.is_read_only();
+        let disk_scheduler =
+            DiskScheduler::with_config(disk_manager, config.disk_workers, RetryPolicy::default());
         Database {
-            filename: None,
-            buffer_pool: Arc::new(BufferPool::new(config.buffer_pool_size, reader)),
+            path,
+            read_only,
+            buffer_pool: Arc::new(BufferPool::with_disk_scheduler(
+                config.buffer_pool_size,
+                disk_scheduler,
+            )),
+        }
+    }
+
+    /// Creates an empty database whose pages live in memory and are lost when it is dropped.
+    /// Growing past `max_memory_pages` fails with `OutOfMemory`. Fails if the page size of the
+    /// config is invalid. With `DatabaseConfig::in_memory` there are no worker threads, so it is
+    /// cheap to create one per test.
+    ///
+    /// Known gap: frames are not backed by the memory storage. Pages are still read and written
+    /// through the buffer pool, so a page in a frame is held twice, in the frame and in the
+    /// memory the frame is flushed to and loaded back from, and is copied every time it moves
+    /// between them.
+    pub fn in_memory(config: DatabaseConfig) -> Result<Database, DatabaseError> {
+        let page_size = config.page_size()?;
+        let backend = match config.max_memory_pages {
+            Some(max_pages) => MemoryBackend::with_max_pages(max_pages),
+            None => MemoryBackend::new(),
+        };
+        let file_options = config.file_options();
+        let backend = backend.with_page_size(file_options.file_page_size(page_size));
+        Ok(Database::with_backend(backend, file_options, config))
+    }
+
+    /// Opens the database stored in the given file, creating it if it doesn't exist. An existing
+    /// database is opened with the page size recorded in its header, a new one is created with
+    /// the page size of the config.
+    pub fn open(path: impl AsRef<Path>, config: DatabaseConfig) -> Result<Database, DatabaseError> {
+        Database::open_with_mode(path.as_ref(), config, OpenMode::Open)
+    }
+
+    /// Opens the database stored in the given file without write permission. Pages can be read
+    /// but not modified, and the file is never written, not even to repair torn pages with the
+    /// double-write buffer. Other read-only databases can have the file open at the same time.
+    pub fn open_read_only(
+        path: impl AsRef<Path>,
+        config: DatabaseConfig,
+    ) -> Result<Database, DatabaseError> {
+        Database::open_with_mode(path.as_ref(), config, OpenMode::ReadOnly)
+    }
+
+    /// Creates a new database in the given file, with the page size of the config. Fails if the
+    /// file already exists.
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: DatabaseConfig,
+    ) -> Result<Database, DatabaseError> {
+        Database::open_with_mode(path.as_ref(), config, OpenMode::Create)
+    }
+
+    fn open_with_mode(
+        path: &Path,
+        config: DatabaseConfig,
+        mode: OpenMode,
+    ) -> Result<Database, DatabaseError> {
+        let page_size = match mode {
+            OpenMode::Create => config.page_size()?,
+            _ => stored_page_size(path)?.map_or_else(|| config.page_size(), Ok)?,
+        };
+        let disk_manager = DiskManager::open(path, page_size, config.file_options(), mode)?;
+        let database = Database::with_disk_manager(disk_manager, config);
+        database.load_header(path)?;
+        database.open_tablespaces()?;
+        Ok(database)
+    }
+
+    /// Writes the header of a database file that was just created, or checks the header of an
+    /// existing one
+    fn load_header(&self, path: &Path) -> Result<(), DatabaseError> {
+        let not_a_database = || DatabaseError::NotADatabase(path.to_path_buf());
+        if self.buffer_pool.disk_manager().allocated_pages(0) == Some(0) {
+            if self.read_only {
+                return Err(not_a_database());
+            }
+            self.buffer_pool
+                .get_page_write(0)?
+                .init_page(PageType::Header);
+            return self.flush();
+        }
+
+        let page = self.buffer_pool.get_page_read(0)?;
+        let header = page.as_header().map_err(|_| not_a_database())?;
+        if !header.has_valid_magic()
+            || header.page_size() != self.page_size()
+            || header.file_id() != 0
+        {
+            return Err(not_a_database());
+        }
+        if header.format_version() != DATABASE_FORMAT_VERSION {
+            return Err(DatabaseError::UnsupportedFormatVersion(
+                header.format_version(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Opens the data files of the tablespaces recorded in the header
+    fn open_tablespaces(&self) -> Result<(), DatabaseError> {
+        let tablespaces = {
+            let page = self.buffer_pool.get_page_read(0)?;
+            let header = page.as_header().map_err(corrupted_header)?;
+            header.tablespaces().map_err(corrupted_header)?
+        };
+        for tablespace in tablespaces {
+            self.buffer_pool.disk_manager().open_tablespace(
+                &tablespace.name,
+                tablespace.file_id,
+                &tablespace.path,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The path of the database file. None if the database is not backed by a file.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The size of the pages of every data file of the database
+    pub fn page_size(&self) -> usize {
+        self.buffer_pool.page_size()
+    }
+
+    /// Creates a tablespace stored in a new data file. Its pages are addressed with the returned
+    /// file id, see `PageAddress`. The tablespace is recorded in the header of the database
+    /// file, with the absolute path of its data file, and is opened with the database from then
+    /// on. Only databases stored in a database file can have tablespaces.
+    pub fn create_tablespace(
+        &self,
+        name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<FileId, DatabaseError> {
+        let disk_manager = self.buffer_pool.disk_manager();
+        if self.read_only {
+            return Err(DatabaseError::ReadOnly);
+        }
+        if self.path.is_none() || self.buffer_pool.get_page_read(0)?.as_header().is_err() {
+            return Err(TablespaceError::NotRecordable.into());
+        }
+        let file_id = disk_manager.create_tablespace(name, path.as_ref())?;
+        if let Err(err) = self.record_tablespace(name, file_id) {
+            disk_manager.remove_tablespace(file_id)?;
+            return Err(err);
         }
+        // Once recorded, the tablespace is kept even if the header can't be written yet, it is
+        // written again with the next flush
+        self.flush()?;
+        Ok(file_id)
+    }
+
+    /// Adds the tablespace stored in the data file with the given id to the header
+    fn record_tablespace(&self, name: &str, file_id: FileId) -> Result<(), DatabaseError> {
+        let path = self
+            .buffer_pool
+            .disk_manager()
+            .file_path(file_id)
+            .expect("Tablespaces are created in files");
+        let entry = TablespaceEntry {
+            name: name.to_string(),
+            file_id,
+            path: std::fs::canonicalize(path)?,
+        };
+        let page = self.buffer_pool.get_page_write(0)?;
+        let mut header = page.as_header().map_err(corrupted_header)?;
+        match header.add_tablespace(&entry).map_err(corrupted_header)? {
+            true => Ok(()),
+            false => Err(TablespaceError::HeaderFull.into()),
+        }
+    }
+
+    /// The file id of the tablespace with the given name
+    pub fn tablespace(&self, name: &str) -> Result<FileId, DatabaseError> {
+        self.buffer_pool
+            .disk_manager()
+            .tablespace(name)
+            .ok_or_else(|| TablespaceError::NotFound(name.to_string()).into())
+    }
+
+    /// Allocates a page in the named tablespace, formatted as an empty page of the given type.
+    /// Tables and indexes are created in a tablespace by allocating their first page in it.
+    #[track_caller]
+    pub fn allocate_page(
+        &self,
+        tablespace: &str,
+        page_type: PageType,
+    ) -> Result<PageWriteGuard, DatabaseError> {
+        let file_id = self.tablespace(tablespace)?;
+        Ok(self.buffer_pool.allocate_page(file_id, page_type)?)
     }
 
-    // TODO: return Result instead
-    pub fn from_file(filename: String, config: DatabaseConfig) -> Database {
-        let file = fs::OpenOptions::new()
-            .read(true)
+    /// Copies the database file to a new file while the database keeps serving readers and
+    /// writers. The copy holds the pages as they were when the backup started, including the
+    /// modifications not flushed yet, and can be opened with `restore`.
+    ///
+    /// Limitation: only the database file is backed up, so this fails if tablespaces are
+    /// attached, since they would be missing from the backup.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<(), DatabaseError> {
+        self.backup_to_with_progress(path, |_| {})
+    }
+
+    /// Like `backup_to`, calling `progress` after each page is copied
+    pub fn backup_to_with_progress(
+        &self,
+        path: impl AsRef<Path>,
+        progress: impl FnMut(BackupProgress),
+    ) -> Result<(), DatabaseError> {
+        let path = path.as_ref();
+        log::debug!("Backing up database {:?} to {path:?}", self.path);
+        if self.buffer_pool.disk_manager().file_ids().len() > 1 {
+            return Err(TablespaceError::BackupUnsupported.into());
+        }
+        // A plain file, whatever the storage of the database, encrypted with the same key
+        let file_options = DataFileOptions {
+            storage: StorageKind::File,
+            double_write: false,
+            encryption_key: self
+                .buffer_pool
+                .disk_manager()
+                .file_options()
+                .encryption_key
+                .clone(),
+        };
+        let target = file_options.open(path, 0, self.page_size(), OpenMode::Create)?;
+        let result = self.buffer_pool.backup(0, target, progress);
+        if result.is_err() {
+            // A partial backup must not be mistaken for a good one
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(result?)
+    }
+
+    /// Creates the database file at `path` from a backup made with `backup_to` and opens it.
+    /// Fails if the file already exists. The config must have the key the database was
+    /// encrypted with, if any.
+    pub fn restore(
+        backup_path: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+        config: DatabaseConfig,
+    ) -> Result<Database, DatabaseError> {
+        let path = path.as_ref();
+        log::debug!(
+            "Restoring database {path:?} from {:?}",
+            backup_path.as_ref()
+        );
+        let mut backup = std::fs::File::open(backup_path)?;
+        let mut file = std::fs::OpenOptions::new()
             .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&filename)
-            .unwrap_or_else(|_| {
-                panic!("Failed to open database file: {:?}", filename);
-            });
+            .create_new(true)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    DatabaseError::AlreadyExists(path.to_path_buf())
+                }
+                _ => DatabaseError::IOError(err),
+            })?;
+        let copied = std::io::copy(&mut backup, &mut file).and_then(|_| file.sync_all());
+        if let Err(err) = copied {
+            let _ = std::fs::remove_file(path);
+            return Err(err.into());
+        }
+        // A leftover double-write buffer belongs to a database that was deleted
+        let _ = std::fs::remove_file(double_write_path(path));
+        Database::open(path, config)
+    }
 
-        Database {
-            filename: Some(filename),
-            buffer_pool: Arc::new(BufferPool::new(config.buffer_pool_size, file)),
+    /// Writes every modified page back to the database file. The data files are synced unless
+    /// durability is off.
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        Ok(self.buffer_pool.flush_all_pages()?)
+    }
+
+    /// Compacts every data file and gives the free pages at its end back to the file system,
+    /// including the extents preallocated when the files grew, and returns how many pages were
+    /// released. Pages are free once given back with `BufferPool::free_page`. The B+tree nodes at
+    /// the end of a file are moved to the free pages before them, see `compact` for which pages
+    /// can be moved. No page guard may be held while it runs.
+    ///
+    /// Databases without a header, like in-memory ones, can't be compacted.
+    pub fn shrink(&mut self) -> Result<u64, DatabaseError> {
+        if self.read_only {
+            return Err(DatabaseError::ReadOnly);
         }
+        let n_released_pages = compact(&self.buffer_pool)?;
+        log::debug!(
+            "Released {n_released_pages} free pages of database {:?}",
+            self.path
+        );
+        Ok(n_released_pages)
+    }
+
+    /// The root page of the catalog, recorded in the header of the database file. None until it
+    /// is set with `set_catalog_root`. The root is kept up to date when `shrink` moves it.
+    pub fn catalog_root(&self) -> Result<Option<PageId>, DatabaseError> {
+        let page = self.buffer_pool.get_page_read(0)?;
+        let root = page.as_header().map_err(corrupted_header)?.catalog_root();
+        Ok((root != INVALID_PAGE_ID).then_some(root))
+    }
+
+    pub fn set_catalog_root(&self, root: PageId) -> Result<(), DatabaseError> {
+        let page = self.buffer_pool.get_page_write(0)?;
+        page.as_header()
+            .map_err(corrupted_header)?
+            .set_catalog_root(root);
+        Ok(())
+    }
+
+    /// Changes the number of frames of the buffer pool without restarting the database
+    pub fn resize_buffer_pool(&self, n_frames: usize) -> Result<(), BufferPoolError> {
+        self.buffer_pool.resize(n_frames)
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("Could not flush the database on drop: {err}");
+        }
+
+        // A guard outliving the database means a pin leak. Pins are only tracked in debug builds.
+        if std::thread::panicking() {
+            return;
+        }
+        let pinned_pages = self.buffer_pool.pinned_pages_report();
+        debug_assert!(
+            pinned_pages.is_empty(),
+            "Database dropped with {} pinned pages:\n{}",
+            pinned_pages.len(),
+            pinned_pages
+                .iter()
+                .map(|page| page.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
     }
 }
 
 pub struct DatabaseConfig {
-    buffer_pool_size: usize,
+    /// Number of frames in the buffer pool
+    pub buffer_pool_size: usize,
+    /// Number of threads doing page IO in parallel. With none, IO is done by the threads using
+    /// the database.
+    pub disk_workers: usize,
+    /// How the database file is accessed by `open` and `create`
+    pub storage: StorageKind,
+    /// Data files grow by this many pages at a time
+    pub extent_pages: u64,
+    /// Encrypts every page on disk with this key. The same key must be given every time the
+    /// database is opened.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Writes pages to a double-write buffer next to each data file before writing them in
+    /// place, so pages torn by a power loss are repaired when the database is opened. Pages of
+    /// databases opened with `from_backend`, or with durability off, are always written in place.
+    pub double_write: bool,
+    /// When writes are synced to disk. Tests don't survive power losses and can turn it off.
+    pub durability: Durability,
+    /// The most pages an `in_memory` database can hold. Unlimited if None. Memory is allocated
+    /// by extents, like data files, and the extent reaching the limit is cut short to fit.
+    pub max_memory_pages: Option<u64>,
+    /// The size of the pages of new databases, a power of two between `MIN_PAGE_SIZE` and
+    /// `MAX_PAGE_SIZE`. Existing databases are opened with the page size in their header, except
+    /// encrypted ones, whose header can't be read without it.
+    pub page_size: usize,
 }
 
 impl Default for DatabaseConfig {
     fn default() -> Self {
         DatabaseConfig {
             buffer_pool_size: BUFFER_POOL_N_FRAMES,
+            disk_workers: DISK_SCHEDULER_N_WORKERS,
+            storage: StorageKind::File,
+            extent_pages: DATA_FILE_EXTENT_PAGES,
+            encryption_key: None,
+            double_write: false,
+            durability: Durability::Full,
+            max_memory_pages: None,
+            page_size: PAGE_SIZE,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// The default config for `Database::in_memory`: IO is served by the threads using the
+    /// database, and memory is allocated one page at a time
+    pub fn in_memory() -> Self {
+        DatabaseConfig {
+            disk_workers: 0,
+            extent_pages: 1,
+            ..DatabaseConfig::default()
+        }
+    }
+
+    /// The double-write buffer only protects pages if it is synced
+    fn double_write(&self) -> bool {
+        self.double_write && self.durability != Durability::Off
+    }
+
+    /// How the disk manager opens the data files of the database
+    fn file_options(&self) -> DataFileOptions {
+        if self.double_write && !self.double_write() {
+            log::warn!("The double-write buffer is disabled because durability is off");
+        }
+        DataFileOptions {
+            storage: self.storage,
+            double_write: self.double_write(),
+            encryption_key: self.encryption_key.clone(),
+        }
+    }
+
+    fn page_size(&self) -> Result<usize, DatabaseError> {
+        if !is_valid_page_size(self.page_size) {
+            return Err(DatabaseError::InvalidPageSize(self.page_size));
         }
+        Ok(self.page_size)
+    }
+}
+
+/// The header was checked when the database was opened, it can only be damaged since
+fn corrupted_header(err: PageError) -> DatabaseError {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("The database header is corrupted: {err}"),
+    )
+    .into()
+}
+
+fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+/// The page size recorded in the header of the database file. None if the file doesn't exist
+/// yet, or if its header can't be read without decrypting it.
+fn stored_page_size(path: &Path) -> Result<Option<usize>, DatabaseError> {
+    let mut first_page = Vec::with_capacity(MIN_PAGE_SIZE);
+    match std::fs::File::open(path) {
+        Ok(file) => file
+            .take(MIN_PAGE_SIZE as u64)
+            .read_to_end(&mut first_page)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if first_page.len() < MIN_PAGE_SIZE {
+        return Ok(None);
+    }
+    match HeaderPage::new(first_page.as_slice()) {
+        Ok(header) if header.has_valid_magic() => match header.page_size() {
+            page_size if is_valid_page_size(page_size) => Ok(Some(page_size)),
+            _ => Err(DatabaseError::NotADatabase(path.to_path_buf())),
+        },
+        _ => Ok(None),
     }
 }
 
@@ -57,30 +516,660 @@ impl Default for DatabaseConfig {
 mod test {
     use super::*;
     use crate::config::PAGE_SIZE;
+    use crate::errors::ScheduleError;
     use crate::shared::logger::setup_logger;
-    use std::io::Cursor;
+    use crate::storage::disk::disk_manager::DEFAULT_TABLESPACE;
+    use crate::storage::{MemoryBackend, PageAddress};
     use std::sync::{atomic::AtomicUsize, Arc};
 
     const TEST_CONCURRENCY: usize = 24;
 
     #[test]
-    fn test_create_database_from_reader() {
+    fn test_create_database_from_backend() {
         setup_logger();
         let database = vec![0u8; PAGE_SIZE];
-        let reader = Cursor::new(database);
-
-        let db = Database::from_buffer(reader, DatabaseConfig::default());
-        assert!(db.filename.is_none());
+        let db = Database::from_backend(MemoryBackend::from(database), DatabaseConfig::default());
+        assert!(db.path().is_none());
         assert_eq!(db.buffer_pool.len(), 0);
     }
 
+    fn temp_database_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("maridbel-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_create_and_reopen_database_file() {
+        setup_logger();
+        for storage in [StorageKind::File, StorageKind::Mmap] {
+            let path = temp_database_path(&format!("reopen-{storage:?}"));
+            let config = || DatabaseConfig {
+                storage,
+                ..DatabaseConfig::default()
+            };
+
+            {
+                let db = Database::create(&path, config()).unwrap();
+                assert_eq!(db.path(), Some(path.as_path()));
+                let page = db.buffer_pool.get_page_write(1).unwrap();
+                page.write().write_at(0, b"persisted");
+            }
+
+            assert!(matches!(
+                Database::create(&path, config()),
+                Err(DatabaseError::AlreadyExists(_))
+            ));
+
+            {
+                // Opening an existing database must not truncate it
+                let db = Database::open(&path, config()).unwrap();
+                let page = db.buffer_pool.get_page_read(1).unwrap();
+                assert_eq!(&page.read().data[..9], b"persisted");
+            }
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_page_size() {
+        setup_logger();
+        let path = temp_database_path("page-size");
+        let archive_path = temp_database_path("page-size-archive");
+        let config = |page_size| DatabaseConfig {
+            page_size,
+            ..DatabaseConfig::default()
+        };
+        for page_size in [1000, 2048, 65536] {
+            assert!(matches!(
+                Database::create(&path, config(page_size)),
+                Err(DatabaseError::InvalidPageSize(_))
+            ));
+        }
+
+        {
+            let db = Database::create(&path, config(16384)).unwrap();
+            let page = db.buffer_pool.get_page_write(1).unwrap();
+            page.write().write_at(16380, b"last");
+            drop(page);
+            db.create_tablespace("archive", &archive_path).unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len() % 16384, 0);
+
+        {
+            // The page size comes from the header, not from the config
+            let db = Database::open(&path, DatabaseConfig::default()).unwrap();
+            assert_eq!(db.page_size(), 16384);
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            assert_eq!(page.read().data.len(), 16384);
+            assert_eq!(&page.read().data[16380..], b"last");
+            drop(page);
+            assert_eq!(db.tablespace("archive").unwrap(), 1);
+        }
+
+        std::fs::write(&path, vec![1u8; PAGE_SIZE]).unwrap();
+        assert!(matches!(
+            Database::open(&path, DatabaseConfig::default()),
+            Err(DatabaseError::NotADatabase(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&archive_path).unwrap();
+    }
+
+    #[test]
+    fn test_shrink() {
+        setup_logger();
+        let path = temp_database_path("shrink");
+        let archive_path = temp_database_path("shrink-archive");
+        let config = || DatabaseConfig {
+            extent_pages: 16,
+            ..DatabaseConfig::default()
+        };
+
+        {
+            let mut db = Database::create(&path, config()).unwrap();
+            db.create_tablespace("archive", &archive_path).unwrap();
+            let allocate =
+                |tablespace, page_type| db.allocate_page(tablespace, page_type).unwrap().page_id();
+            // Pages 1 to 4, freed but the last one to leave holes
+            let heap: Vec<_> = (0..4)
+                .map(|_| allocate(DEFAULT_TABLESPACE, PageType::Slotted))
+                .collect();
+            // Nothing links to this node, so it can't be moved
+            let lone_leaf = allocate(DEFAULT_TABLESPACE, PageType::BTreeLeaf);
+            // A B+tree whose root is the catalog root
+            let root = allocate(DEFAULT_TABLESPACE, PageType::BTreeInternal);
+            let first_leaf = allocate(DEFAULT_TABLESPACE, PageType::BTreeLeaf);
+            let last_leaf = allocate(DEFAULT_TABLESPACE, PageType::BTreeLeaf);
+            assert_eq!([lone_leaf, root, first_leaf, last_leaf], [5, 6, 7, 8]);
+            {
+                let page = db.buffer_pool.get_page_write(root).unwrap();
+                let mut node = page.as_btree_internal().unwrap();
+                node.set_leftmost_child(first_leaf);
+                node.set_len(1);
+                node.set_entry(0, 100, last_leaf);
+                let page = db.buffer_pool.get_page_write(first_leaf).unwrap();
+                let mut leaf = page.as_btree_leaf().unwrap();
+                leaf.set_next_leaf(last_leaf);
+                leaf.set_len(1);
+                leaf.set_entry(0, 1, 10);
+                let page = db.buffer_pool.get_page_write(last_leaf).unwrap();
+                let mut leaf = page.as_btree_leaf().unwrap();
+                leaf.set_len(1);
+                leaf.set_entry(0, 100, 20);
+            }
+            db.set_catalog_root(root).unwrap();
+            for page_id in &heap[..3] {
+                db.buffer_pool.free_page(*page_id).unwrap();
+            }
+            let archive_pages = [
+                allocate("archive", PageType::Slotted),
+                allocate("archive", PageType::Slotted),
+            ];
+            db.buffer_pool.free_page(archive_pages[1]).unwrap();
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().len(),
+                16 * PAGE_SIZE as u64
+            );
+
+            // The tree moves to the holes, the lone leaf stays
+            assert_eq!(db.shrink().unwrap(), 10 + 14);
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().len(),
+                6 * PAGE_SIZE as u64
+            );
+            assert_eq!(
+                std::fs::metadata(&archive_path).unwrap().len(),
+                2 * PAGE_SIZE as u64
+            );
+            assert_eq!(db.shrink().unwrap(), 0);
+        }
+
+        {
+            let db = Database::open(&path, config()).unwrap();
+            // The links to the moved nodes were rewritten
+            let root = db.catalog_root().unwrap().unwrap();
+            assert_eq!(root, 3);
+            let page = db.buffer_pool.get_page_read(root).unwrap();
+            let node = page.as_btree_internal().unwrap();
+            assert_eq!((node.leftmost_child(), node.child_at(0)), (2, 1));
+            let page = db.buffer_pool.get_page_read(2).unwrap();
+            let leaf = page.as_btree_leaf().unwrap();
+            assert_eq!(
+                (leaf.next_leaf(), leaf.key_at(0), leaf.value_at(0)),
+                (1, 1, 10)
+            );
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            let leaf = page.as_btree_leaf().unwrap();
+            assert_eq!((leaf.key_at(0), leaf.value_at(0)), (100, 20));
+            assert!(db
+                .buffer_pool
+                .get_page_read(4)
+                .unwrap()
+                .as_slotted()
+                .is_ok());
+            assert!(db
+                .buffer_pool
+                .get_page_read(5)
+                .unwrap()
+                .as_btree_leaf()
+                .is_ok());
+            // Every hole was filled, new pages go after the last page kept
+            let page = db
+                .allocate_page(DEFAULT_TABLESPACE, PageType::Slotted)
+                .unwrap();
+            assert_eq!(page.page_id(), 6);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&archive_path).unwrap();
+    }
+
+    #[test]
+    fn test_shrink_without_header() {
+        setup_logger();
+        let mut db = Database::in_memory(DatabaseConfig::in_memory()).unwrap();
+        assert!(matches!(
+            db.shrink(),
+            Err(DatabaseError::BufferPoolError(BufferPoolError::PageError(
+                PageError::PageTypeMismatch { .. }
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_tablespaces() {
+        setup_logger();
+        let path = temp_database_path("tablespaces");
+        let archive_path = temp_database_path("tablespaces-archive");
+        let other_path = temp_database_path("tablespaces-other");
+
+        let archive_page = {
+            let db = Database::create(&path, DatabaseConfig::default()).unwrap();
+            let file_id = db.create_tablespace("archive", &archive_path).unwrap();
+            assert_eq!(file_id, 1);
+            assert_eq!(db.tablespace("archive").unwrap(), file_id);
+            assert!(matches!(
+                db.create_tablespace("archive", &other_path),
+                Err(DatabaseError::TablespaceError(TablespaceError::NameInUse(
+                    _
+                )))
+            ));
+            assert!(!other_path.exists());
+            // An existing file is never overwritten nor deleted
+            assert!(matches!(
+                db.create_tablespace("other", &archive_path),
+                Err(DatabaseError::AlreadyExists(_))
+            ));
+            assert!(archive_path.exists());
+            assert_eq!(db.create_tablespace("other", &other_path).unwrap(), 2);
+            // The backup would miss the tablespace
+            let backup_path = temp_database_path("tablespaces-backup");
+            assert!(matches!(
+                db.backup_to(&backup_path),
+                Err(DatabaseError::TablespaceError(
+                    TablespaceError::BackupUnsupported
+                ))
+            ));
+            assert!(!backup_path.exists());
+
+            // The same page number in two files are two different pages
+            let archive_page = db.allocate_page("archive", PageType::Slotted).unwrap();
+            archive_page.as_slotted().unwrap().insert_tuple(b"archive");
+            assert_eq!(
+                archive_page.page_id(),
+                PageAddress::new(file_id, 1).page_id()
+            );
+            let default_page = db
+                .allocate_page(DEFAULT_TABLESPACE, PageType::Slotted)
+                .unwrap();
+            default_page.as_slotted().unwrap().insert_tuple(b"default");
+            assert_eq!(default_page.page_id(), 1);
+            let next_page = db.allocate_page("archive", PageType::Slotted).unwrap();
+            assert_eq!(next_page.page_id(), PageAddress::new(file_id, 2).page_id());
+            assert!(matches!(
+                db.allocate_page("missing", PageType::Slotted),
+                Err(DatabaseError::TablespaceError(TablespaceError::NotFound(_)))
+            ));
+            archive_page.page_id()
+        };
+        assert_eq!(
+            std::fs::metadata(&archive_path).unwrap().len(),
+            DATA_FILE_EXTENT_PAGES * PAGE_SIZE as u64
+        );
+
+        // The tablespaces recorded in the header are opened with the database
+        for read_only in [false, true] {
+            let db = match read_only {
+                false => Database::open(&path, DatabaseConfig::default()).unwrap(),
+                true => Database::open_read_only(&path, DatabaseConfig::default()).unwrap(),
+            };
+            assert_eq!(db.tablespace("archive").unwrap(), 1);
+            assert_eq!(db.tablespace("other").unwrap(), 2);
+            let page = db.buffer_pool.get_page_read(archive_page).unwrap();
+            let tuple = page
+                .as_slotted()
+                .unwrap()
+                .get_n_tuple(0)
+                .unwrap()
+                .as_bytes()
+                .to_vec();
+            assert_eq!(tuple, b"archive");
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            let tuple = page
+                .as_slotted()
+                .unwrap()
+                .get_n_tuple(0)
+                .unwrap()
+                .as_bytes()
+                .to_vec();
+            assert_eq!(tuple, b"default");
+            if !read_only {
+                // Pages keep being allocated past the ones allocated before
+                let page = db.allocate_page("archive", PageType::Slotted).unwrap();
+                assert_eq!(page.page_id(), PageAddress::new(1, 3).page_id());
+            }
+        }
+
+        // The header of a data file tells which file it is
+        let swapped_path = temp_database_path("tablespaces-swapped");
+        std::fs::rename(&archive_path, &swapped_path).unwrap();
+        std::fs::rename(&other_path, &archive_path).unwrap();
+        assert!(matches!(
+            Database::open(&path, DatabaseConfig::default()),
+            Err(DatabaseError::TablespaceError(
+                TablespaceError::FileIdMismatch {
+                    expected: 1,
+                    found: 2
+                }
+            ))
+        ));
+        std::fs::rename(&archive_path, &other_path).unwrap();
+        assert!(matches!(
+            Database::open(&path, DatabaseConfig::default()),
+            Err(DatabaseError::IOError(_))
+        ));
+        assert!(!archive_path.exists());
+        // A data file is not a database
+        assert!(matches!(
+            Database::open(&other_path, DatabaseConfig::default()),
+            Err(DatabaseError::NotADatabase(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&swapped_path).unwrap();
+        std::fs::remove_file(&other_path).unwrap();
+    }
+
+    #[test]
+    fn test_tablespaces_need_a_database_file() {
+        setup_logger();
+        let path = temp_database_path("in-memory-archive");
+        let db = Database::in_memory(DatabaseConfig::in_memory()).unwrap();
+        assert!(matches!(
+            db.create_tablespace("archive", &path),
+            Err(DatabaseError::TablespaceError(
+                TablespaceError::NotRecordable
+            ))
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_concurrent_tablespace_creation() {
+        setup_logger();
+        let path = temp_database_path("concurrent-tablespaces");
+        let tablespace_paths: Vec<_> = (0..8)
+            .map(|i| temp_database_path(&format!("concurrent-tablespaces-{i}")))
+            .collect();
+        {
+            let db = Database::create(&path, DatabaseConfig::default()).unwrap();
+            let mut file_ids: Vec<_> = std::thread::scope(|s| {
+                let handles: Vec<_> = (0..8)
+                    .map(|i| {
+                        let (db, path) = (&db, &tablespace_paths[i]);
+                        s.spawn(move || db.create_tablespace(&format!("t{i}"), path).unwrap())
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            file_ids.sort_unstable();
+            assert_eq!(file_ids, (1..=8).collect::<Vec<_>>());
+        }
+
+        let db = Database::open(&path, DatabaseConfig::default()).unwrap();
+        for (i, tablespace_path) in tablespace_paths.iter().enumerate() {
+            let file_id = db.tablespace(&format!("t{i}")).unwrap();
+            assert_eq!(
+                db.buffer_pool
+                    .disk_manager()
+                    .file_path(file_id)
+                    .unwrap()
+                    .file_name(),
+                tablespace_path.file_name()
+            );
+        }
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+        for path in tablespace_paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_encrypted_database() {
+        setup_logger();
+        let path = temp_database_path("encrypted");
+        let config = |key: u8| DatabaseConfig {
+            encryption_key: Some(EncryptionKey::from_bytes([key; 32])),
+            ..DatabaseConfig::default()
+        };
+
+        {
+            let db = Database::create(&path, config(1)).unwrap();
+            let page = db.buffer_pool.get_page_write(1).unwrap();
+            page.write().write_at(0, b"customer PII");
+        }
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents.windows(12).any(|bytes| bytes == b"customer PII"));
+
+        {
+            let db = Database::open(&path, config(1)).unwrap();
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            assert_eq!(&page.read().data[..12], b"customer PII");
+        }
+        // The header can't be read with another key
+        assert!(matches!(
+            Database::open(&path, config(2)),
+            Err(DatabaseError::BufferPoolError(
+                BufferPoolError::SchedulerError(ScheduleError::CorruptedPage(_))
+            ))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        setup_logger();
+        let path = temp_database_path("backup-source");
+        let backup_path = temp_database_path("backup");
+        let restored_path = temp_database_path("backup-restored");
+        let config = || DatabaseConfig {
+            extent_pages: 4,
+            encryption_key: Some(EncryptionKey::from_bytes([7; 32])),
+            ..DatabaseConfig::default()
+        };
+
+        {
+            let db = Database::create(&path, config()).unwrap();
+            // Not flushed yet, the backup takes it from the buffer pool
+            let page = db.buffer_pool.get_page_write(1).unwrap();
+            page.write().write_at(0, b"backed up");
+            drop(page);
+
+            let mut n_steps = 0;
+            db.backup_to_with_progress(&backup_path, |_| n_steps += 1)
+                .unwrap();
+            assert_eq!(n_steps, 4);
+            assert!(matches!(
+                db.backup_to(&backup_path),
+                Err(DatabaseError::AlreadyExists(_))
+            ));
+
+            let page = db.buffer_pool.get_page_write(1).unwrap();
+            page.write().write_at(0, b"after");
+        }
+
+        {
+            let db = Database::restore(&backup_path, &restored_path, config()).unwrap();
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            assert_eq!(&page.read().data[..9], b"backed up");
+        }
+        assert!(matches!(
+            Database::restore(&backup_path, &restored_path, config()),
+            Err(DatabaseError::AlreadyExists(_))
+        ));
+
+        for path in [path, backup_path, restored_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_double_write() {
+        setup_logger();
+        let path = temp_database_path("double-write");
+        let config = || DatabaseConfig {
+            double_write: true,
+            ..DatabaseConfig::default()
+        };
+
+        {
+            let db = Database::create(&path, config()).unwrap();
+            let page = db.buffer_pool.get_page_write(1).unwrap();
+            page.write().write_at(0, b"written twice");
+        }
+        let region_path = double_write_path(&path);
+        let region = std::fs::read(&region_path).unwrap();
+        assert!(region.windows(13).any(|bytes| bytes == b"written twice"));
+
+        // Tear the page in place, the copy is still in the double-write buffer
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[PAGE_SIZE + 4..PAGE_SIZE * 2].fill(0);
+        std::fs::write(&path, contents).unwrap();
+        {
+            let db = Database::open(&path, config()).unwrap();
+            let page = db.buffer_pool.get_page_read(1).unwrap();
+            assert_eq!(&page.read().data[..13], b"written twice");
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&region_path).unwrap();
+    }
+
+    #[test]
+    fn test_database_file_is_locked() {
+        setup_logger();
+        for storage in [StorageKind::File, StorageKind::Mmap] {
+            let path = temp_database_path("locked");
+            let config = || DatabaseConfig {
+                storage,
+                ..DatabaseConfig::default()
+            };
+
+            let db = Database::create(&path, config()).unwrap();
+            assert!(matches!(
+                Database::open(&path, config()),
+                Err(DatabaseError::DatabaseLocked(_))
+            ));
+            drop(db);
+            let db = Database::open(&path, config()).unwrap();
+            drop(db);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_only_database() {
+        setup_logger();
+        for storage in [StorageKind::File, StorageKind::Mmap] {
+            let path = temp_database_path("read-only");
+            let config = || DatabaseConfig {
+                storage,
+                ..DatabaseConfig::default()
+            };
+            assert!(matches!(
+                Database::open_read_only(&path, config()),
+                Err(DatabaseError::IOError(_))
+            ));
+            assert!(!path.exists());
+            {
+                let db = Database::create(&path, config()).unwrap();
+                let page = db.buffer_pool.get_page_write(1).unwrap();
+                page.write().write_at(0, b"read me");
+            }
+            let contents = std::fs::read(&path).unwrap();
+
+            {
+                let db = Database::open_read_only(&path, config()).unwrap();
+                let other = Database::open_read_only(&path, config()).unwrap();
+                assert!(matches!(
+                    Database::open(&path, config()),
+                    Err(DatabaseError::DatabaseLocked(_))
+                ));
+
+                let page = db.buffer_pool.get_page_read(1).unwrap();
+                assert_eq!(&page.read().data[..7], b"read me");
+                assert!(page.try_upgrade().is_err());
+                assert!(matches!(
+                    db.buffer_pool.get_page_write(1),
+                    Err(BufferPoolError::ReadOnly)
+                ));
+                // Pages past the end are empty and the file doesn't grow
+                let page = other.buffer_pool.get_page_read(1000).unwrap();
+                assert!(page.read().data.iter().all(|byte| *byte == 0));
+                drop(page);
+                assert!(matches!(
+                    db.create_tablespace("archive", temp_database_path("read-only-archive")),
+                    Err(DatabaseError::ReadOnly)
+                ));
+            }
+            assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_in_memory_database() {
+        setup_logger();
+        let db = Database::in_memory(DatabaseConfig {
+            max_memory_pages: Some(3),
+            ..DatabaseConfig::in_memory()
+        })
+        .unwrap();
+        assert_eq!(db.path(), None);
+        assert!(matches!(
+            Database::in_memory(DatabaseConfig {
+                page_size: 1000,
+                ..DatabaseConfig::in_memory()
+            }),
+            Err(DatabaseError::InvalidPageSize(1000))
+        ));
+
+        for page_id in 0..3 {
+            let page = db.buffer_pool.get_page_write(page_id).unwrap();
+            page.write().write_at(0, &[page_id as u8 + 1]);
+        }
+        assert!(matches!(
+            db.buffer_pool.get_page_read(3),
+            Err(BufferPoolError::SchedulerError(ScheduleError::OutOfMemory))
+        ));
+        db.flush().unwrap();
+        for page_id in 0..3 {
+            let page = db.buffer_pool.get_page_read(page_id).unwrap();
+            assert_eq!(page.read().data[0], page_id as u8 + 1);
+        }
+
+        // Extents larger than the limit are cut short to fit
+        let db = Database::in_memory(DatabaseConfig {
+            max_memory_pages: Some(3),
+            ..DatabaseConfig::default()
+        })
+        .unwrap();
+        for page_id in 0..3 {
+            drop(db.buffer_pool.get_page_write(page_id).unwrap());
+        }
+        assert_eq!(db.buffer_pool.disk_manager().allocated_pages(0), Some(3));
+        assert!(db.buffer_pool.get_page_read(3).is_err());
+    }
+
+    #[test]
+    fn test_unknown_data_file() {
+        setup_logger();
+        let db = Database::in_memory(DatabaseConfig::in_memory()).unwrap();
+        let page_id = PageAddress::new(7, 0).page_id();
+        assert!(db.buffer_pool.get_page_read(page_id).is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Database dropped with 1 pinned pages")]
+    fn test_database_drop_with_pinned_pages() {
+        setup_logger();
+        let db = Database::from_backend(MemoryBackend::new(), DatabaseConfig::default());
+        std::mem::forget(db.buffer_pool.get_page_read(0).unwrap());
+    }
+
     #[test]
     fn test_database_multiple_readers() {
         setup_logger();
         let data = vec![7u8; PAGE_SIZE];
-        let reader = Cursor::new(data);
-
-        let db = Database::from_buffer(reader, DatabaseConfig::default());
+        let db = Database::from_backend(MemoryBackend::from(data), DatabaseConfig::default());
         let mut threads = Vec::with_capacity(TEST_CONCURRENCY);
 
         let n_bytes_read = Arc::new(AtomicUsize::new(0));
@@ -117,9 +1206,7 @@ mod test {
     fn test_database_multiple_writers_and_reader() {
         setup_logger();
         let data = vec![]; // empty database
-        let reader = Cursor::new(data);
-
-        let db = Database::from_buffer(reader, DatabaseConfig::default());
+        let db = Database::from_backend(MemoryBackend::from(data), DatabaseConfig::default());
         let mut threads = Vec::with_capacity(TEST_CONCURRENCY);
 
         for i in 0..TEST_CONCURRENCY {
@@ -127,7 +1214,7 @@ mod test {
 
             let t = std::thread::spawn(move || {
                 let page = cloned_buffer_pool.get_page_write(0).expect("TODO: HANDLE");
-                page.write().data = vec![i as u8; PAGE_SIZE].into();
+                page.write().data_mut().fill(i as u8);
             });
             threads.push(t);
         }
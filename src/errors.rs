@@ -1,9 +1,20 @@
 use std::error::Error;
+use std::path::PathBuf;
+
+use crate::storage::{FileId, PageType};
 
 #[derive(Debug)]
 pub enum ScheduleError {
     IOError(std::io::Error),
+    /// The disk is full and the data file could not grow.
+    OutOfSpace,
+    /// The in-memory storage reached the number of pages it is limited to.
+    OutOfMemory,
+    /// The page read from disk is not the one that was written, it was corrupted or tampered.
+    CorruptedPage(std::io::Error),
     UnexpectedEof,
+    /// The scheduler was shut down, or its worker died, and doesn't accept requests anymore.
+    SchedulerShutdown,
     Unknown,
 }
 
@@ -17,8 +28,74 @@ pub enum BufferPoolError {
     PageDirty,
     /// The requested page is not pinned.
     PageNotPinned,
+    /// The page changed, or was latched for writing, during a non-blocking read. The caller
+    /// should restart.
+    PageVersionChanged,
+    /// The data files are open read-only, pages can't be modified.
+    ReadOnly,
+    /// Another backup is running.
+    BackupInProgress,
+    /// The backup could not be written.
+    BackupError(std::io::Error),
     /// Derived error from the scheduler
     SchedulerError(ScheduleError),
+    /// A page the buffer pool reads itself, like the header of a data file, is damaged.
+    PageError(PageError),
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The database file could not be opened or created.
+    IOError(std::io::Error),
+    /// The database file was expected to not exist.
+    AlreadyExists(PathBuf),
+    /// Another process, or another database in this one, has the database file open.
+    DatabaseLocked(PathBuf),
+    /// The database was opened read-only and can't be modified.
+    ReadOnly,
+    /// The file doesn't start with a valid database header.
+    NotADatabase(PathBuf),
+    /// The database was written in an on-disk format this version can't read.
+    UnsupportedFormatVersion(u32),
+    /// The page size is not a power of two between `MIN_PAGE_SIZE` and `MAX_PAGE_SIZE`.
+    InvalidPageSize(usize),
+    /// Derived error from the buffer pool
+    BufferPoolError(BufferPoolError),
+    /// Derived error from the tablespaces of the disk manager
+    TablespaceError(TablespaceError),
+}
+
+#[derive(Debug)]
+pub enum TablespaceError {
+    /// Another tablespace already has this name.
+    NameInUse(String),
+    /// Another data file is already attached with this id.
+    FileIdInUse(FileId),
+    /// No tablespace has this name.
+    NotFound(String),
+    /// The data file doesn't have the page size of the database.
+    PageSizeMismatch { expected: usize, found: usize },
+    /// The header of the data file was written for another file id.
+    FileIdMismatch { expected: FileId, found: FileId },
+    /// The header of the database file has no room left to record another tablespace.
+    HeaderFull,
+    /// The database has no header to record the tablespace in, it isn't stored in a database
+    /// file.
+    NotRecordable,
+    /// Backups only copy the database file, so they can't be taken with tablespaces attached.
+    BackupUnsupported,
+}
+
+#[derive(Debug)]
+pub enum PageError {
+    /// The page is tagged with another type than the one it is being read as.
+    PageTypeMismatch { expected: PageType, found: u8 },
+    /// The page is tagged with a type that doesn't exist.
+    UnknownPageType(u8),
+    /// The page has no slot with this number.
+    SlotOutOfBounds(usize),
+    /// The page layout is inconsistent, it was damaged on disk or never formatted.
+    CorruptedPage(String),
 }
 
 impl std::fmt::Display for BufferPoolError {
@@ -28,9 +105,16 @@ impl std::fmt::Display for BufferPoolError {
             BufferPoolError::PageNotFound => write!(f, "Page not found in buffer pool"),
             BufferPoolError::PageDirty => write!(f, "Page is dirty and cannot be evicted"),
             BufferPoolError::PageNotPinned => write!(f, "Page is not pinned"),
+            BufferPoolError::PageVersionChanged => {
+                write!(f, "Page changed during non-blocking read")
+            }
+            BufferPoolError::ReadOnly => write!(f, "The buffer pool is read-only"),
+            BufferPoolError::BackupInProgress => write!(f, "Another backup is running"),
+            BufferPoolError::BackupError(err) => write!(f, "Backup failed: {}", err),
             BufferPoolError::SchedulerError(schedule_error) => {
                 write!(f, "Scheduler error: {:?}", schedule_error)
             }
+            BufferPoolError::PageError(err) => write!(f, "Page error: {}", err),
         }
     }
 }
@@ -39,15 +123,108 @@ impl std::fmt::Display for ScheduleError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ScheduleError::IOError(err) => write!(f, "IO error: {}", err),
+            ScheduleError::OutOfSpace => write!(f, "No space left on the device"),
+            ScheduleError::OutOfMemory => {
+                write!(f, "The in-memory storage reached its size limit")
+            }
+            ScheduleError::CorruptedPage(err) => write!(f, "Corrupted page: {}", err),
             ScheduleError::UnexpectedEof => write!(f, "Unexpected EOF"),
+            ScheduleError::SchedulerShutdown => write!(f, "Disk scheduler was shut down"),
             ScheduleError::Unknown => write!(f, "Unknown error"),
         }
     }
 }
 
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::IOError(err) => write!(f, "IO error: {}", err),
+            DatabaseError::AlreadyExists(path) => {
+                write!(f, "Database file already exists: {:?}", path)
+            }
+            DatabaseError::DatabaseLocked(path) => {
+                write!(f, "Database file is in use by another process: {:?}", path)
+            }
+            DatabaseError::ReadOnly => write!(f, "The database is open read-only"),
+            DatabaseError::NotADatabase(path) => write!(f, "Not a database file: {:?}", path),
+            DatabaseError::UnsupportedFormatVersion(version) => {
+                write!(f, "Unsupported database format version {version}")
+            }
+            DatabaseError::InvalidPageSize(page_size) => {
+                write!(f, "Invalid page size {page_size}")
+            }
+            DatabaseError::BufferPoolError(err) => write!(f, "Buffer pool error: {}", err),
+            DatabaseError::TablespaceError(err) => write!(f, "Tablespace error: {}", err),
+        }
+    }
+}
+
+impl std::fmt::Display for TablespaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TablespaceError::NameInUse(name) => write!(f, "Tablespace {name} already exists"),
+            TablespaceError::FileIdInUse(file_id) => {
+                write!(f, "Data file {file_id} is already attached")
+            }
+            TablespaceError::NotFound(name) => write!(f, "Tablespace {name} doesn't exist"),
+            TablespaceError::PageSizeMismatch { expected, found } => write!(
+                f,
+                "Data file has {found} bytes pages instead of the {expected} bytes of the database"
+            ),
+            TablespaceError::FileIdMismatch { expected, found } => {
+                write!(f, "Data file {found} was opened as data file {expected}")
+            }
+            TablespaceError::HeaderFull => {
+                write!(
+                    f,
+                    "The database header has no room left for another tablespace"
+                )
+            }
+            TablespaceError::NotRecordable => {
+                write!(
+                    f,
+                    "Only databases stored in a database file can have tablespaces"
+                )
+            }
+            TablespaceError::BackupUnsupported => {
+                write!(f, "Databases with tablespaces can't be backed up")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageError::PageTypeMismatch { expected, found } => match PageType::from_tag(*found) {
+                Some(found) => write!(
+                    f,
+                    "Expected a {:?} page but found a {:?} page",
+                    expected, found
+                ),
+                None => write!(f, "Expected a {:?} page but found tag {}", expected, found),
+            },
+            PageError::UnknownPageType(tag) => write!(f, "Unknown page type tag {}", tag),
+            PageError::SlotOutOfBounds(n) => write!(f, "Slot {} out of bounds", n),
+            PageError::CorruptedPage(reason) => write!(f, "Corrupted page: {}", reason),
+        }
+    }
+}
+
 impl std::convert::From<BufferPoolError> for std::io::Error {
     fn from(err: BufferPoolError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, err)
+        std::io::Error::other(err)
+    }
+}
+
+impl std::convert::From<std::io::Error> for ScheduleError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::StorageFull => ScheduleError::OutOfSpace,
+            std::io::ErrorKind::OutOfMemory => ScheduleError::OutOfMemory,
+            std::io::ErrorKind::InvalidData => ScheduleError::CorruptedPage(err),
+            _ => ScheduleError::IOError(err),
+        }
     }
 }
 
@@ -57,5 +234,32 @@ impl std::convert::From<ScheduleError> for BufferPoolError {
     }
 }
 
+impl std::convert::From<PageError> for BufferPoolError {
+    fn from(err: PageError) -> Self {
+        BufferPoolError::PageError(err)
+    }
+}
+
+impl std::convert::From<std::io::Error> for DatabaseError {
+    fn from(err: std::io::Error) -> Self {
+        DatabaseError::IOError(err)
+    }
+}
+
+impl std::convert::From<BufferPoolError> for DatabaseError {
+    fn from(err: BufferPoolError) -> Self {
+        DatabaseError::BufferPoolError(err)
+    }
+}
+
+impl std::convert::From<TablespaceError> for DatabaseError {
+    fn from(err: TablespaceError) -> Self {
+        DatabaseError::TablespaceError(err)
+    }
+}
+
 impl Error for BufferPoolError {}
+impl Error for DatabaseError {}
 impl Error for ScheduleError {}
+impl Error for PageError {}
+impl Error for TablespaceError {}
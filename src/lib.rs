@@ -5,25 +5,70 @@ mod macros;
 // For submodules I only expose the public API to the parent module
 
 pub mod storage {
-    mod page;
     mod tuple;
 
+    pub mod page {
+        mod btree_page;
+        mod free_page;
+        mod header_page;
+        mod layout;
+        mod links;
+        mod slotted_page;
+
+        pub use btree_page::{BTreeInternalPage, BTreeLeafPage};
+        pub use free_page::{FreePage, FREE_PAGE_MAGIC};
+        pub use header_page::{
+            HeaderPage, TablespaceEntry, DATABASE_FORMAT_VERSION, DATABASE_MAGIC,
+        };
+        pub(crate) use layout::reset_page;
+        pub use layout::{
+            page_type, FileId, PageAddress, PageData, PageDataMut, PageId, PageNo, PageType,
+            INVALID_PAGE_ID,
+        };
+        pub use links::{page_links, set_link, PageLink};
+        pub use slotted_page::SlottedPage;
+    }
+
     pub mod disk {
+        mod data_file;
         pub mod disk_manager;
         pub mod disk_scheduler;
+        mod double_write;
+        mod encryption;
+        #[cfg(any(test, feature = "fault_injection"))]
+        mod fault_injection;
+        #[cfg(feature = "io_uring")]
+        mod io_uring_worker;
+        mod mmap_backend;
+        mod storage_backend;
+
+        pub use data_file::{DataFileOptions, OpenMode, StorageKind};
+        pub use double_write::{double_write_path, DoubleWriteBackend};
+        pub use encryption::{EncryptedBackend, EncryptionKey, ENCRYPTION_TRAILER_SIZE};
+        #[cfg(any(test, feature = "fault_injection"))]
+        pub use fault_injection::{FaultInjectionBackend, FaultInjector};
+        pub use mmap_backend::MmapBackend;
+        pub use storage_backend::{FileBackend, MemoryBackend, StorageBackend};
     }
 
     pub mod buffer {
         pub mod buffer_pool;
+        pub mod compaction;
         mod eviction;
         pub mod frame;
         mod lruk_eviction;
+        pub mod pin_tracker;
+        pub mod snapshot;
     }
 
     pub use buffer::buffer_pool::BufferPool;
     pub use buffer::frame::Frame;
-    pub use disk::disk_manager::DiskManager;
-    pub use page::{PageId, SlottedPage};
+    pub use disk::disk_manager::{DiskManager, Durability};
+    pub use disk::{
+        DoubleWriteBackend, EncryptedBackend, EncryptionKey, FileBackend, MemoryBackend,
+        MmapBackend, StorageBackend,
+    };
+    pub use page::{FileId, PageAddress, PageId, PageNo, PageType, SlottedPage};
 }
 
 pub mod catalog {
@@ -32,7 +77,8 @@ pub mod catalog {
 
 pub mod dbms {
     mod database;
-    pub use database::Database;
+    pub use crate::storage::disk::StorageKind;
+    pub use database::{Database, DatabaseConfig};
 }
 
 pub mod shared {
@@ -1,25 +1,36 @@
 use super::eviction::EvictionPolicy;
-use super::frame::{Frame, FrameId, PageReadGuard, PageWriteGuard};
+use super::frame::{
+    Frame, FrameId, FramePin, PageReadGuard, PageTryReadGuard, PageWriteGuard, PinCounts,
+};
 use super::lruk_eviction::LRUKEvictionPolicy;
-use crate::config::{LRU_K, PAGE_SIZE};
+use super::pin_tracker::{PinTracker, PinnedPage};
+use super::snapshot::{BackupProgress, Snapshot, SnapshotSlot};
+use crate::config::{LRU_K, PIN_LEAK_THRESHOLD};
 use crate::errors::BufferPoolError;
-use crate::storage::disk::disk_scheduler::DiskScheduler;
-use crate::storage::PageId;
+use crate::storage::disk::disk_manager::DiskManager;
+use crate::storage::disk::disk_scheduler::{wait_for, DiskScheduler, IoPriority};
+use crate::storage::page::FreePage;
+use crate::storage::{FileId, PageAddress, PageId, PageNo, PageType, StorageBackend};
 
 use std::collections::HashMap;
-use std::io::{Read, Seek, Write};
+use std::panic::Location;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 /// # Design principles
 ///
 /// - Data locality: a page only stores tuples that are in the same table.
-/// - Simplicity: no page directory is neede because page ids represent offsets in the DB file.
+/// - Simplicity: no page directory is neede because page ids represent offsets in the data files.
 pub struct BufferPool {
-    /// The size of the buffer pool in number of frames
-    pool_size: usize,
+    /// The size of the buffer pool in number of frames. It can be changed at runtime with
+    /// `resize`, so there may temporarily be more frames than this while surplus frames are
+    /// still pinned.
+    pool_size: AtomicUsize,
     /// Stores the metadata of the pages in the buffer pool
     /// The buffer pool must guarantee that all entries here are loaded in memory.
-    frames: Vec<Arc<RwLock<Frame>>>,
+    /// Frames with an id greater or equal than pool_size are surplus frames waiting to be retired.
+    frames: RwLock<Vec<Arc<RwLock<Frame>>>>,
     /// Maps page id to buffer pool frame id. Returns None if the page is not in the buffer pool.
     page_table: Arc<RwLock<HashMap<PageId, FrameId>>>,
     /// The list of available frames for allocation. Getting a free frame is O(1).
@@ -29,33 +40,46 @@ pub struct BufferPool {
     disk_scheduler: DiskScheduler,
     /// The eviction policy to use when the buffer pool is full.
     eviction_policy: Arc<dyn EvictionPolicy + Send + Sync>,
+    /// How many guards pin each frame, shared with every guard
+    pin_counts: Arc<PinCounts>,
+    /// Records where every live guard was created to find pin leaks. Only active in debug builds.
+    pin_tracker: Arc<PinTracker>,
+    /// The snapshot of a running backup, shared with every guard
+    snapshots: Arc<SnapshotSlot>,
 }
 
 impl BufferPool {
     /// Creates a new buffer pool manager with the given size
-    pub fn new<R>(pool_size: usize, reader: R) -> Self
-    where
-        R: Read + Write + Seek + Send + 'static,
-    {
-        let mut frames = Vec::with_capacity(pool_size);
+    pub fn new(pool_size: usize, disk_manager: DiskManager) -> Self {
+        BufferPool::with_disk_scheduler(pool_size, DiskScheduler::new(disk_manager))
+    }
+
+    /// Creates a new buffer pool manager on top of an already configured disk scheduler
+    pub fn with_disk_scheduler(pool_size: usize, disk_scheduler: DiskScheduler) -> Self {
+        assert!(
+            pool_size <= FrameId::MAX as usize,
+            "Buffer pool size exceeds the maximum number of frames",
+        );
         let page_table = HashMap::with_capacity(pool_size);
 
         //  TODO: log to the console that the database is allocating the buffer pool
-        for _ in 0..pool_size {
-            let data = vec![0u8; PAGE_SIZE].into_boxed_slice();
-            frames.push(Arc::new(RwLock::new(Frame::new(data))));
-        }
+        let page_size = disk_scheduler.disk_manager().page_size();
+        let frames = (0..pool_size).map(|_| new_frame(page_size)).collect();
 
         let free_list = (0..pool_size as FrameId).collect();
-        let disk_scheduler = DiskScheduler::new(reader);
+        let eviction_policy: Arc<dyn EvictionPolicy + Send + Sync> =
+            Arc::new(LRUKEvictionPolicy::new(LRU_K, pool_size));
 
         BufferPool {
-            pool_size,
-            frames,
+            pool_size: AtomicUsize::new(pool_size),
+            frames: RwLock::new(frames),
             free_list: Arc::new(RwLock::new(free_list)),
             page_table: Arc::new(RwLock::new(page_table)),
-            eviction_policy: Arc::new(LRUKEvictionPolicy::new(LRU_K, pool_size)),
+            pin_counts: Arc::new(PinCounts::new(pool_size, eviction_policy.clone())),
+            eviction_policy,
             disk_scheduler,
+            pin_tracker: Arc::new(PinTracker::new(PIN_LEAK_THRESHOLD)),
+            snapshots: Arc::new(SnapshotSlot::default()),
         }
     }
 
@@ -64,105 +88,410 @@ impl BufferPool {
     //       - the disk scheduler panicked
     // TODO: Acquiring a full lock over the page_table is a bad design choice. get_page_read
     //       should be possible to be called multiple times at the same time for different page ids
+    #[track_caller]
     pub fn get_page_read(&self, page_id: PageId) -> Result<PageReadGuard, BufferPoolError> {
         log::trace!("BufferPool::get_page_read({page_id})");
-        // We acquire exclusive lock over the page table because we may potentially write to
-        // it in the "None" branch
-        let mut page_table = self.page_table.write().expect("page table was poisoned");
-        let maybe_frame_id = page_table.get(&page_id).cloned();
-
-        match maybe_frame_id {
-            Some(frame_id) => {
-                // We are not writing to the page table so release the lock inmediatly.
-                drop(page_table);
+        self.pin_tracker.warn_long_pinned_pages();
+        loop {
+            let mut pin = self.pin_page(page_id)?;
+            if self.disk_manager().is_read_only() {
+                pin.set_read_only();
+            }
+            // Latched once the page table is released, so waiting for a writer of this page
+            // doesn't hold up the lookups of other pages
+            let guard = PageReadGuard::from_pin(pin);
+            if guard.read().page_id == Some(page_id) {
+                return Ok(guard);
+            }
+            log::trace!("Frame of page_id={page_id} was reused before it was latched, retrying");
+        }
+    }
 
-                log::trace!("Found page_id={page_id} in frame_id={frame_id}");
-                assert!(
-                    frame_id < self.pool_size as FrameId,
-                    "Frame id out of bounds",
-                );
-                let frame = self.frames.get(frame_id as usize).unwrap();
-                Ok(PageReadGuard::new(
-                    frame_id,
-                    frame.clone(),
-                    self.eviction_policy.clone(),
-                ))
+    /// Returns a write (exclusive) guard for a frame, efectively pinning it.
+    /// If no free frame is available, it will ask the replacer to evict a frame.
+    /// If no frame can be evicted, it fails with BufferPoolFull.
+    /// It fails with ReadOnly if the data files are open read-only.
+    #[track_caller]
+    pub fn get_page_write(&self, page_id: PageId) -> Result<PageWriteGuard, BufferPoolError> {
+        log::trace!("BufferPool::get_page_write({page_id})");
+        if self.disk_manager().is_read_only() {
+            return Err(BufferPoolError::ReadOnly);
+        }
+        self.pin_tracker.warn_long_pinned_pages();
+        loop {
+            let guard = PageWriteGuard::from_pin(self.pin_page(page_id)?);
+            if guard.write().page_id == Some(page_id) {
+                return Ok(guard);
             }
+            log::trace!("Frame of page_id={page_id} was reused before it was latched, retrying");
+        }
+    }
+
+    /// Returns a guard whose reads never block on a writer, see `PageTryReadGuard`. The frame is
+    /// not pinned, so reads through the guard are validated against the frame version and fail if
+    /// the page changed or got evicted.
+    /// If the page is not in the buffer pool, it is loaded from disk first.
+    pub fn get_page_try_read(&self, page_id: PageId) -> Result<PageTryReadGuard, BufferPoolError> {
+        log::trace!("BufferPool::get_page_try_read({page_id})");
+        let maybe_frame = {
+            let page_table = self.page_table.read().expect("page table was poisoned");
+            page_table
+                .get(&page_id)
+                .map(|frame_id| self.frame(*frame_id))
+        };
+
+        let frame = match maybe_frame {
+            Some(frame) => frame,
             None => {
-                log::trace!("Page id={page_id} not found in buffer pool. Fetching from disk");
-                let free_frame_id = self
-                    .try_get_free_frane()
-                    .expect("Buffer pool is full. No free frame found.");
+                let guard = self.get_page_read(page_id)?;
+                self.frame(guard.frame_id())
+            }
+        };
+        let version = {
+            let frame = frame.read().unwrap();
+            if frame.page_id != Some(page_id) {
+                return Err(BufferPoolError::PageVersionChanged);
+            }
+            frame.version
+        };
+        Ok(PageTryReadGuard::new(page_id, frame, version))
+    }
 
-                log::trace!("Found empty frame_id={free_frame_id}. Loading page id={page_id}");
-                page_table.insert(page_id, free_frame_id);
+    /// Changes the number of frames of the buffer pool.
+    ///
+    /// Growing adds the new frames to the free list. Shrinking flushes and removes the pages held
+    /// by the surplus frames. Surplus frames that are still pinned keep working until they are
+    /// unpinned, and they are retired the next time the buffer pool looks for a free frame.
+    pub fn resize(&self, new_pool_size: usize) -> Result<(), BufferPoolError> {
+        assert!(
+            new_pool_size <= FrameId::MAX as usize,
+            "Buffer pool size exceeds the maximum number of frames",
+        );
+        log::debug!("BufferPool::resize({new_pool_size})");
+        let mut page_table = self.page_table.write().expect("page table was poisoned");
+        let old_pool_size = self.pool_size.swap(new_pool_size, Ordering::SeqCst);
 
-                self.load_page_from_disk(page_id, free_frame_id)?;
-                log::trace!("Loaded page_id={page_id} into frame_id={free_frame_id} from disk");
+        if new_pool_size > old_pool_size {
+            let mut frames = self.frames.write().unwrap();
+            let mut free_list = self.free_list.write().unwrap();
 
-                let frame = self
-                    .frames
-                    .get(free_frame_id as usize)
-                    .unwrap_or_else(|| panic!("Frame id={free_frame_id} out of bounds"));
+            // Surplus frames from a previous shrink that are still around become regular frames
+            // again. The ones that were already emptied go back to the free list.
+            for frame_id in old_pool_size..new_pool_size.min(frames.len()) {
+                if frames[frame_id].read().unwrap().page_id.is_none() {
+                    free_list.push(frame_id as FrameId);
+                }
+            }
+            for frame_id in frames.len()..new_pool_size {
+                frames.push(new_frame(self.page_size()));
+                free_list.push(frame_id as FrameId);
+            }
+            self.pin_counts.resize(frames.len());
+        } else {
+            self.free_list
+                .write()
+                .unwrap()
+                .retain(|frame_id| (*frame_id as usize) < new_pool_size);
+        }
+
+        self.retire_surplus_frames(&mut page_table)
+    }
 
-                Ok(PageReadGuard::new(
-                    free_frame_id,
+    /// Writes every dirty page in the buffer pool back to disk and syncs the data files, unless
+    /// durability is off. This is the checkpoint. Pages stay in the buffer pool.
+    pub fn flush_all_pages(&self) -> Result<(), BufferPoolError> {
+        let page_table = self.page_table.read().expect("page table was poisoned");
+        let mut dirty_pages: Vec<_> = page_table
+            .iter()
+            .filter_map(|(page_id, frame_id)| {
+                let frame = self.frame(*frame_id);
+                // The flag is cleared before writing so a concurrent modification makes the
+                // page dirty again instead of being lost
+                let mut latch = frame.write().unwrap();
+                if !latch.is_dirty {
+                    return None;
+                }
+                latch.is_dirty = false;
+                drop(latch);
+                log::trace!("Flushing dirty page_id={page_id} from frame_id={frame_id}");
+                Some((*page_id, frame))
+            })
+            .collect();
+        // Scheduling every write before waiting, in page order, lets the disk scheduler merge
+        // adjacent pages into a single write
+        dirty_pages.sort_unstable_by_key(|(page_id, _)| *page_id);
+        let writes: Vec<_> = dirty_pages
+            .into_iter()
+            .map(|(page_id, frame)| {
+                let result = self.disk_scheduler.schedule_write(
+                    page_id,
                     frame.clone(),
-                    self.eviction_policy.clone(),
-                ))
+                    IoPriority::Background,
+                );
+                (frame, result)
+            })
+            .collect();
+
+        let mut first_error = None;
+        for (frame, result) in writes {
+            if let Err(err) = result.and_then(wait_for) {
+                frame.write().unwrap().is_dirty = true;
+                first_error.get_or_insert(err);
             }
         }
+        if let Some(err) = first_error {
+            return Err(err.into());
+        }
+        self.disk_manager()
+            .sync()
+            .map_err(|err| BufferPoolError::SchedulerError(err.into()))
     }
 
-    /// Returns a write (exclusive) guard for a frame, efectively pinning it.
-    /// If no free frame is available, it will ask the replacer to evict a frame.
-    /// If no frame can be evicted, it will block until a frame is available.
-    pub fn get_page_write(&self, page_id: PageId) -> Result<PageWriteGuard, BufferPoolError> {
-        log::trace!("BufferPool::get_page_write({page_id})");
-        // We acquire exclusive lock over the page because we may potentially write to
-        // the table in the "None" branch
+    /// Hands out a page of the data file nothing uses, formatted as an empty page of the given
+    /// type, and returns it latched for writing. Pages given back with `free_page` are handed
+    /// out first, then pages past the ones recorded in the header of the data file, so a data
+    /// file must not mix allocated pages with pages addressed directly past them.
+    #[track_caller]
+    pub fn allocate_page(
+        &self,
+        file_id: FileId,
+        page_type: PageType,
+    ) -> Result<PageWriteGuard, BufferPoolError> {
+        log::trace!("BufferPool::allocate_page({file_id})");
+        assert_ne!(
+            page_type,
+            PageType::Free,
+            "Allocated pages are formatted as pages in use"
+        );
+        // The header stays latched until the page is formatted, so no one else gets it
+        let header_page = self.get_page_write(PageAddress::new(file_id, 0).page_id())?;
+        let mut header = header_page.as_header()?;
+        let head = header.free_list_head();
+        if head != 0 {
+            let page_id = PageAddress::new(file_id, head).page_id();
+            let page = self.get_page_write(page_id)?;
+            let next = FreePage::new(page.write(), page_id).map(|free| free.next());
+            match next {
+                Ok(next) => {
+                    header.set_free_list(next, header.n_free_pages().saturating_sub(1));
+                    page.init_page(page_type);
+                    return Ok(page);
+                }
+                // The rest of the list is lost until the next compaction finds its pages
+                Err(err) => {
+                    log::warn!("Dropping the broken free list of data file {file_id}: {err}");
+                    header.set_free_list(0, 0);
+                }
+            }
+        }
+
+        let page_no = header.n_pages();
+        let page = self.get_page_write(PageAddress::new(file_id, page_no).page_id())?;
+        page.init_page(page_type);
+        header.set_n_pages(page_no + 1);
+        Ok(page)
+    }
+
+    /// Gives the page back to its data file: it is formatted as a free page and pushed on the
+    /// free list recorded in the header of the file, so `allocate_page` hands it out again and
+    /// compaction can give it back to the file system. Freeing a free page does nothing.
+    #[track_caller]
+    pub fn free_page(&self, page_id: PageId) -> Result<(), BufferPoolError> {
+        log::trace!("BufferPool::free_page({page_id})");
+        let address = PageAddress::of(page_id);
+        assert_ne!(
+            address.page_no, 0,
+            "The header of a data file can't be freed"
+        );
+        let header_page = self.get_page_write(PageAddress::new(address.file_id, 0).page_id())?;
+        let mut header = header_page.as_header()?;
+        let page = self.get_page_write(page_id)?;
+        if FreePage::new(page.write(), page_id).is_ok() {
+            return Ok(());
+        }
+        FreePage::init(page.write(), page_id, header.free_list_head());
+        header.set_free_list(address.page_no, header.n_free_pages() + 1);
+        Ok(())
+    }
+
+    /// Gives back to the file system the pages of the data file past its first `n_pages` pages,
+    /// and returns how many pages were released. Pages modified but not flushed yet, or pinned,
+    /// are kept, along with the pages before them. The frames holding pages past the new end
+    /// are emptied.
+    pub fn truncate_file(&self, file_id: FileId, n_pages: u64) -> Result<u64, BufferPoolError> {
+        log::debug!("BufferPool::truncate_file({file_id}, {n_pages})");
+        if self.disk_manager().is_read_only() {
+            return Err(BufferPoolError::ReadOnly);
+        }
+        // No page can be loaded or written back while the file shrinks
         let mut page_table = self.page_table.write().expect("page table was poisoned");
-        let maybe_frame_id = page_table.get(&page_id).cloned();
+        let n_allocated_pages = self
+            .disk_manager()
+            .allocated_pages(file_id)
+            .ok_or_else(|| {
+                BufferPoolError::SchedulerError(
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("No data file with id {file_id}"),
+                    )
+                    .into(),
+                )
+            })?;
+        let mut n_kept_pages = n_pages.min(n_allocated_pages);
 
-        match maybe_frame_id {
-            Some(frame_id) => {
-                // We are not writing to the page table so release the lock inmediatly.
-                drop(page_table);
-                assert!(
-                    frame_id < self.pool_size as FrameId,
-                    "Frame id out of bounds",
-                );
-                let frame = self.frames.get(frame_id as usize).unwrap();
-                Ok(PageWriteGuard::new(
-                    frame_id,
-                    frame.clone(),
-                    self.eviction_policy.clone(),
-                ))
+        let mut past_end = Vec::new();
+        for (page_id, frame_id) in page_table.iter() {
+            let address = PageAddress::of(*page_id);
+            if address.file_id != file_id || (address.page_no as u64) < n_kept_pages {
+                continue;
             }
-            None => {
-                log::trace!("Page id={page_id} not found in buffer pool. Fetching from disk");
-                let free_frame_id = self
-                    .try_get_free_frane()
-                    .expect("Buffer pool is full. No free frame found.");
+            let frame = self.frame(*frame_id);
+            let latch = frame.read().unwrap();
+            if latch.is_dirty || self.pin_counts.get(*frame_id) > 0 {
+                n_kept_pages = n_kept_pages.max(address.page_no as u64 + 1);
+            }
+            drop(latch);
+            past_end.push((address.page_no, *frame_id, frame));
+        }
+        for (page_no, frame_id, frame) in past_end {
+            if (page_no as u64) < n_kept_pages {
+                continue;
+            }
+            // Clean and unpinned, nothing is written
+            self.detach_frame(frame_id, &frame, &mut page_table)?;
+            if (frame_id as usize) < self.pool_size() {
+                self.free_list.write().unwrap().push(frame_id);
+            }
+        }
 
-                log::trace!("Found empty frame_id={free_frame_id}. Loading page id={page_id}");
-                page_table.insert(page_id, free_frame_id);
+        self.disk_manager()
+            .truncate(file_id, n_kept_pages)
+            .map_err(|err| BufferPoolError::SchedulerError(err.into()))?;
+        Ok(n_allocated_pages - n_kept_pages)
+    }
 
-                self.load_page_from_disk(page_id, free_frame_id)?;
-                log::trace!("Loaded page_id={page_id} into frame_id={free_frame_id} from disk");
+    /// Every page currently pinned by a guard along with where the guard was created, the oldest
+    /// pins first. Pins are only tracked in debug builds, in release builds it is always empty.
+    pub fn pinned_pages_report(&self) -> Vec<PinnedPage> {
+        self.pin_tracker.pinned_pages()
+    }
 
-                let frame = self
-                    .frames
-                    .get(free_frame_id as usize)
-                    .unwrap_or_else(|| panic!("Frame id={free_frame_id} out of bounds"));
+    /// Changes how long a page can stay pinned before it is reported as a possible leak
+    pub fn set_pin_leak_threshold(&self, threshold: Duration) {
+        self.pin_tracker.set_threshold(threshold);
+    }
 
-                Ok(PageWriteGuard::new(
-                    free_frame_id,
-                    frame.clone(),
-                    self.eviction_policy.clone(),
+    /// Copies the pages of the data file, as they are when the backup starts, to `target` while
+    /// writers keep modifying them. Pages in the buffer pool, modified or not, are copied from
+    /// their frames, the others are read from disk without loading them, so the backup doesn't
+    /// evict the pages in use. `progress` is called after each page is copied.
+    pub fn backup(
+        &self,
+        file_id: FileId,
+        target: Box<dyn StorageBackend>,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<(), BufferPoolError> {
+        let n_pages = self
+            .disk_manager()
+            .allocated_pages(file_id)
+            .ok_or_else(|| {
+                BufferPoolError::BackupError(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No data file with id {file_id}"),
                 ))
+            })?;
+        log::debug!("BufferPool::backup({file_id}) of {n_pages} pages");
+        let snapshot = Arc::new(Snapshot::new(file_id, n_pages, target));
+        if !self.snapshots.install(snapshot.clone()) {
+            return Err(BufferPoolError::BackupInProgress);
+        }
+        let _clear = ClearSnapshotOnDrop(&self.snapshots);
+
+        for page_no in 0..snapshot.n_pages() {
+            let page_id = PageAddress::new(file_id, page_no as PageNo).page_id();
+            // Pages a writer copied before modifying them are not read again
+            if !snapshot.is_copied(page_id) {
+                self.copy_to_snapshot(&snapshot, page_id)?;
             }
+            snapshot
+                .write_pending()
+                .map_err(BufferPoolError::BackupError)?;
+            progress(snapshot.progress());
         }
+        snapshot.finish().map_err(BufferPoolError::BackupError)
+    }
+
+    /// Copies the page to the snapshot from its frame, or from disk if it is not in the buffer
+    /// pool
+    fn copy_to_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        page_id: PageId,
+    ) -> Result<(), BufferPoolError> {
+        loop {
+            let maybe_frame = {
+                let page_table = self.page_table.read().expect("page table was poisoned");
+                page_table
+                    .get(&page_id)
+                    .map(|frame_id| self.frame(*frame_id))
+            };
+            let Some(frame) = maybe_frame else {
+                // Pages are written back before they leave the buffer pool, so the page on disk
+                // is up to date. Writers load it and copy it to the snapshot before modifying it,
+                // in which case this copy comes too late and is ignored.
+                let mut data = vec![0; self.page_size()];
+                self.disk_manager()
+                    .read_page(page_id, &mut data)
+                    .map_err(|err| BufferPoolError::SchedulerError(err.into()))?;
+                snapshot.copy_page(page_id, &data);
+                return Ok(());
+            };
+            let frame = frame.read().unwrap();
+            // The frame was given to another page after the page table was released
+            if frame.page_id == Some(page_id) {
+                snapshot.copy_page(page_id, &frame.data);
+                return Ok(());
+            }
+        }
+    }
+
+    /// The disk manager pages are read from and written to
+    pub fn disk_manager(&self) -> &DiskManager {
+        self.disk_scheduler.disk_manager()
+    }
+
+    /// The size of the pages and of the frames holding them
+    pub fn page_size(&self) -> usize {
+        self.disk_manager().page_size()
+    }
+
+    /// The number of frames the buffer pool is configured to have
+    pub fn pool_size(&self) -> usize {
+        self.pool_size.load(Ordering::SeqCst)
+    }
+
+    /// Finds a free frame for the page and reads the page into it from disk
+    fn load_page(
+        &self,
+        page_id: PageId,
+        page_table: &mut HashMap<PageId, FrameId>,
+    ) -> Result<FrameId, BufferPoolError> {
+        let free_frame_id = self
+            .try_get_free_frame(page_table)?
+            .ok_or(BufferPoolError::BufferPoolFull)?;
+
+        log::trace!("Found empty frame_id={free_frame_id}. Loading page id={page_id}");
+        page_table.insert(page_id, free_frame_id);
+
+        if let Err(err) = self.load_page_from_disk(page_id, free_frame_id) {
+            page_table.remove(&page_id);
+            self.frame(free_frame_id).write().unwrap().page_id = None;
+            self.free_list.write().unwrap().push(free_frame_id);
+            return Err(err);
+        }
+        log::trace!("Loaded page_id={page_id} into frame_id={free_frame_id} from disk");
+
+        Ok(free_frame_id)
     }
 
     fn load_page_from_disk(
@@ -170,51 +499,149 @@ impl BufferPool {
         page_id: PageId,
         frame_id: FrameId,
     ) -> Result<(), BufferPoolError> {
-        let frame = self
-            .frames
-            .get(frame_id as usize)
-            .unwrap_or_else(|| panic!("Frame id={frame_id} out of bounds"));
+        let frame = self.frame(frame_id);
 
         {
-            frame.write().unwrap().page_id = Some(page_id);
+            let mut frame = frame.write().unwrap();
+            frame.page_id = Some(page_id);
+            frame.version += 1;
         }
 
-        let receiver = self.disk_scheduler.schedule_read(page_id, frame.clone());
-        receiver
-            .recv()
-            .expect("Disk scheduler sender dropped/panicked but that's illegal.");
-        Ok(())
+        Ok(self
+            .disk_scheduler
+            .read_page(page_id, frame.clone(), IoPriority::Foreground)?)
     }
 
-    fn try_get_free_frane(&self) -> Option<FrameId> {
-        match self.free_list.write().unwrap().pop() {
-            Some(free_frame_id) => return Some(free_frame_id),
-            _ => {}
-        };
+    fn try_get_free_frame(
+        &self,
+        page_table: &mut HashMap<PageId, FrameId>,
+    ) -> Result<Option<FrameId>, BufferPoolError> {
+        self.retire_surplus_frames(page_table)?;
+
+        if let Some(free_frame_id) = self.free_list.write().unwrap().pop() {
+            return Ok(Some(free_frame_id));
+        }
 
         // TODO: calling evit simultaneously from multiple threads is not safe
-        match self.eviction_policy.evict() {
-            Some(evicted_frame_id) => {
-                let evicted_frame_lock = self
-                    .frames
-                    .get(evicted_frame_id as usize)
-                    .unwrap_or_else(|| panic!("Frame id={evicted_frame_id} out of bounds"));
-
-                let mut evicted_frame = evicted_frame_lock.write().unwrap();
-                evicted_frame.page_id = None;
-                // remove this in release
-                assert!(evicted_frame.pin_count == 0);
-
-                if evicted_frame.is_dirty {
-                    self.disk_scheduler
-                        .schedule_write(evicted_frame.page_id.unwrap(), evicted_frame_lock.clone());
-                    // TODO: evicted frame should've be removed inmediatly after eviction
-                    self.eviction_policy.remove(evicted_frame_id);
-                }
-                Some(evicted_frame_id)
+        while let Some(evicted_frame_id) = self.eviction_policy.evict() {
+            let evicted_frame = self.frame(evicted_frame_id);
+            // remove this in release
+            assert!(self.pin_counts.get(evicted_frame_id) == 0);
+
+            self.detach_frame(evicted_frame_id, &evicted_frame, page_table)?;
+
+            // A surplus frame was evicted, it will be retired instead of reused
+            if (evicted_frame_id as usize) < self.pool_size() {
+                return Ok(Some(evicted_frame_id));
             }
-            _ => None,
         }
+        Ok(None)
+    }
+
+    /// Empties the unpinned surplus frames left by a shrink, and drops them from the frames
+    /// vector once all the frames after them are unpinned too.
+    fn retire_surplus_frames(
+        &self,
+        page_table: &mut HashMap<PageId, FrameId>,
+    ) -> Result<(), BufferPoolError> {
+        let pool_size = self.pool_size();
+        let surplus_frames: Vec<_> = {
+            let frames = self.frames.read().unwrap();
+            if frames.len() <= pool_size {
+                return Ok(());
+            }
+            frames[pool_size..].to_vec()
+        };
+
+        let is_pinned = |i: usize| self.pin_counts.get((pool_size + i) as FrameId) > 0;
+        for (i, frame) in surplus_frames.iter().enumerate() {
+            if !is_pinned(i) {
+                self.detach_frame((pool_size + i) as FrameId, frame, page_table)?;
+            }
+        }
+
+        // Only the frames at the end of the vector can be dropped, because the ids of the
+        // frames before them must stay valid
+        let n_retired = (0..surplus_frames.len())
+            .rev()
+            .take_while(|i| !is_pinned(*i))
+            .count();
+
+        let mut frames = self.frames.write().unwrap();
+        let new_len = frames.len() - n_retired;
+        frames.truncate(new_len);
+        self.pin_counts.resize(new_len);
+        log::debug!("Retired {n_retired} surplus frames, {new_len} frames left");
+        Ok(())
+    }
+
+    /// Writes the page held by the frame back to disk if it is dirty and removes it from the
+    /// buffer pool. The caller must make sure that the frame is not pinned.
+    fn detach_frame(
+        &self,
+        frame_id: FrameId,
+        frame: &Arc<RwLock<Frame>>,
+        page_table: &mut HashMap<PageId, FrameId>,
+    ) -> Result<(), BufferPoolError> {
+        let (page_id, is_dirty) = {
+            let frame = frame.read().unwrap();
+            (frame.page_id, frame.is_dirty)
+        };
+        self.eviction_policy.remove(frame_id);
+
+        let Some(page_id) = page_id else {
+            return Ok(());
+        };
+        if is_dirty {
+            log::trace!("Flushing dirty page_id={page_id} from frame_id={frame_id}");
+            // Someone is waiting for the frame to load another page
+            self.disk_scheduler
+                .write_page(page_id, frame.clone(), IoPriority::Foreground)?;
+        }
+
+        page_table.remove(&page_id);
+        let mut frame = frame.write().unwrap();
+        frame.page_id = None;
+        frame.is_dirty = false;
+        frame.version += 1;
+        Ok(())
+    }
+
+    /// Pins the frame holding the page, loading the page from disk if it is not in the buffer
+    /// pool. The frame is not latched, the page table lock is only held while looking it up.
+    #[track_caller]
+    fn pin_page(&self, page_id: PageId) -> Result<FramePin, BufferPoolError> {
+        // We acquire exclusive lock over the page table because we may potentially write to
+        // it if the page is not loaded
+        let mut page_table = self.page_table.write().expect("page table was poisoned");
+        let frame_id = match page_table.get(&page_id) {
+            Some(frame_id) => {
+                log::trace!("Found page_id={page_id} in frame_id={frame_id}");
+                *frame_id
+            }
+            None => {
+                log::trace!("Page id={page_id} not found in buffer pool. Fetching from disk");
+                self.load_page(page_id, &mut page_table)?
+            }
+        };
+        // Pinned before releasing the page table so the frame can't be evicted in between
+        let mut pin = FramePin::new(frame_id, self.frame(frame_id), self.pin_counts.clone());
+        drop(page_table);
+        let pin_token = self
+            .pin_tracker
+            .register(page_id, frame_id, Location::caller());
+        pin.set_pin_token(pin_token);
+        pin.set_snapshots(self.snapshots.clone());
+        Ok(pin)
+    }
+
+    fn frame(&self, frame_id: FrameId) -> Arc<RwLock<Frame>> {
+        self.frames
+            .read()
+            .unwrap()
+            .get(frame_id as usize)
+            .unwrap_or_else(|| panic!("Frame id={frame_id} out of bounds"))
+            .clone()
     }
 
     pub fn load_free_page(&self) {
@@ -232,6 +659,643 @@ impl BufferPool {
     }
 }
 
-// TODO: write buffer pool tests
+/// Stops copying pages for writers when the backup ends, even if it failed
+struct ClearSnapshotOnDrop<'a>(&'a SnapshotSlot);
+
+impl Drop for ClearSnapshotOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn new_frame(page_size: usize) -> Arc<RwLock<Frame>> {
+    let data = vec![0u8; page_size].into_boxed_slice();
+    Arc::new(RwLock::new(Frame::new(data)))
+}
+
 // TODO: Continue busy looping for that failing test
 //       while RUST_BACKTRACE=full cargo test -- --nocapture; do false; done
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::errors::{PageError, ScheduleError};
+    use crate::shared::logger::setup_logger;
+    use crate::storage::disk::FaultInjectionBackend;
+    use crate::storage::page::{PageType, INVALID_PAGE_ID};
+    use crate::storage::{Durability, MemoryBackend};
+
+    #[test]
+    fn test_page_guard_upgrade_and_downgrade() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+
+        let reader = pool.get_page_read(0).unwrap();
+        assert_eq!(reader.read().data[0], 0);
+
+        let writer = reader.try_upgrade().ok().expect("nobody wrote the page");
+        writer.write().write_at(0, &[42]);
+
+        let reader = writer.downgrade();
+        assert_eq!(reader.read().data[0], 42);
+        assert_eq!(pool.pin_counts.get(reader.frame_id()), 1);
+    }
+
+    #[test]
+    fn test_page_guard_upgrade_fails_after_concurrent_write() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+
+        let reader = pool.get_page_read(0).unwrap();
+        assert_eq!(reader.read().data[0], 0);
+
+        {
+            let writer = pool.get_page_write(0).unwrap();
+            writer.write().write_at(0, &[1]);
+        }
+
+        let reader = match reader.try_upgrade() {
+            Ok(_) => panic!("upgrade must fail after the page was written"),
+            Err(reader) => reader,
+        };
+        // Reading again refreshes the observed version
+        assert_eq!(reader.read().data[0], 1);
+        let upgraded = reader.try_upgrade().ok().expect("nobody wrote the page");
+        let version = upgraded.version_at_upgrade().unwrap();
+        assert_eq!(upgraded.write().version, version);
+
+        // The upgraded guard doesn't keep other writers out between its latches
+        pool.get_page_write(0).unwrap().write().write_at(0, &[2]);
+        assert_ne!(upgraded.write().version, version);
+    }
+
+    #[test]
+    fn test_waiting_for_a_latch_doesnt_block_other_pages() {
+        setup_logger();
+        let pool = Arc::new(BufferPool::new(4, DiskManager::new(MemoryBackend::new())));
+        let writer = pool.get_page_write(0).unwrap();
+        let latch = writer.write();
+
+        let reader = std::thread::spawn({
+            let pool = pool.clone();
+            move || pool.get_page_read(0).unwrap().read().data[0]
+        });
+        // Give the reader time to wait for the latch
+        std::thread::sleep(Duration::from_millis(50));
+        // Other pages are still served while the reader waits
+        drop(pool.get_page_read(1).unwrap());
+
+        drop(latch);
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_page_write_guard_dirty_tracking() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+
+        let writer = pool.get_page_write(0).unwrap();
+        // Latching the frame without modifying it must not make it dirty
+        assert!(!writer.write().is_dirty);
+        assert!(writer.modified_ranges().is_empty());
+
+        {
+            let mut frame = writer.write();
+            frame.write_at(8, &[1, 2, 3, 4]);
+            frame.write_at(100, &[5]);
+            frame.range_mut(12..16).fill(6);
+            assert!(frame.is_dirty);
+        }
+        assert_eq!(writer.modified_ranges(), vec![8..16, 100..101]);
+
+        writer.write().range_mut(0..200).fill(0);
+        assert_eq!(writer.modified_ranges(), vec![0..200]);
+    }
+
+    #[test]
+    fn test_evicted_dirty_pages_are_flushed() {
+        setup_logger();
+        let pool = BufferPool::new(2, DiskManager::new(MemoryBackend::new()));
+
+        for page_id in 0..6 {
+            let writer = pool.get_page_write(page_id).unwrap();
+            writer.write().write_at(0, &[page_id as u8 + 1]);
+        }
+        assert_eq!(pool.len(), 2);
+
+        for page_id in 0..6 {
+            let reader = pool.get_page_read(page_id).unwrap();
+            assert_eq!(reader.read().data[0], page_id as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_buffer_pool_resize() {
+        setup_logger();
+        let pool = BufferPool::new(2, DiskManager::new(MemoryBackend::new()));
+
+        pool.resize(4).unwrap();
+        assert_eq!(pool.pool_size(), 4);
+        let guards: Vec<_> = (0..4).map(|id| pool.get_page_write(id).unwrap()).collect();
+        for (page_id, guard) in guards.iter().enumerate() {
+            guard.write().write_at(0, &[page_id as u8 + 1]);
+        }
+        assert!(matches!(
+            pool.get_page_read(4),
+            Err(BufferPoolError::BufferPoolFull)
+        ));
+
+        // Pinned surplus frames keep their pages until they are unpinned
+        pool.resize(1).unwrap();
+        assert_eq!(pool.frames.read().unwrap().len(), 4);
+        assert_eq!(pool.len(), 4);
+        drop(guards);
+
+        let reader = pool.get_page_read(5).unwrap();
+        assert_eq!(pool.frames.read().unwrap().len(), 1);
+        assert_eq!(pool.len(), 1);
+        drop(reader);
+
+        // The surplus pages were flushed before their frames were retired
+        for page_id in 0..4 {
+            let reader = pool.get_page_read(page_id).unwrap();
+            assert_eq!(reader.read().data[0], page_id as u8 + 1);
+        }
+
+        pool.resize(3).unwrap();
+        let guards: Vec<_> = (0..3).map(|id| pool.get_page_read(id).unwrap()).collect();
+        assert_eq!(pool.len(), 3);
+        drop(guards);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_pinned_pages_report() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+        pool.set_pin_leak_threshold(Duration::ZERO);
+
+        let reader = pool.get_page_read(1).unwrap();
+        let line = line!() - 1;
+        let writer = pool.get_page_write(2).unwrap().downgrade();
+
+        let report = pool.pinned_pages_report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].page_id, 1);
+        assert_eq!(report[0].location.file(), file!());
+        assert_eq!(report[0].location.line(), line);
+        // Converting a guard keeps its original registration
+        assert_eq!(report[1].page_id, 2);
+
+        drop(reader);
+        drop(writer);
+        assert!(pool.pinned_pages_report().is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_pinned_pages_report_order() {
+        setup_logger();
+        let pool = BufferPool::new(8, DiskManager::new(MemoryBackend::new()));
+        // Pins taken in a row can share the same instant, they are still reported in order
+        let guards: Vec<_> = (0..8)
+            .map(|page_id| pool.get_page_read(page_id).unwrap())
+            .collect();
+        let report = pool.pinned_pages_report();
+        assert_eq!(
+            report.iter().map(|page| page.page_id).collect::<Vec<_>>(),
+            (0..8).collect::<Vec<_>>()
+        );
+        assert!(report
+            .windows(2)
+            .all(|pages| pages[0].pinned_for >= pages[1].pinned_for));
+        drop(guards);
+    }
+
+    #[test]
+    fn test_typed_page_views() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+
+        {
+            let writer = pool.get_page_write(0).unwrap();
+            assert_eq!(writer.page_type().unwrap(), PageType::Free);
+            writer.init_page(PageType::Slotted);
+
+            let mut page = writer.as_slotted().unwrap();
+            assert_eq!(page.insert_tuple(b"first"), Some(0));
+            assert_eq!(page.insert_tuple(b"second"), Some(1));
+        }
+
+        let reader = pool.get_page_read(0).unwrap();
+        let page = reader.as_slotted().unwrap();
+        assert_eq!(page.n_slots(), 2);
+        assert_eq!(page.get_n_tuple(0).unwrap().as_bytes(), b"first");
+        assert_eq!(page.get_n_tuple(1).unwrap().as_bytes(), b"second");
+        drop(page);
+
+        assert!(matches!(
+            reader.as_btree_leaf(),
+            Err(PageError::PageTypeMismatch {
+                expected: PageType::BTreeLeaf,
+                found: 2,
+            })
+        ));
+        assert!(reader.as_header().is_err());
+    }
+
+    #[test]
+    fn test_corrupted_slotted_page() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+        let writer = pool.get_page_write(0).unwrap();
+        writer.init_page(PageType::Slotted);
+        writer.as_slotted().unwrap().insert_tuple(b"tuple");
+        assert!(matches!(
+            writer.as_slotted().unwrap().get_n_tuple(1),
+            Err(PageError::SlotOutOfBounds(1))
+        ));
+
+        // The slot points past the end of the page
+        writer.write().write_at(6, &u16::MAX.to_be_bytes());
+        assert!(matches!(
+            writer.as_slotted().unwrap().get_n_tuple(0),
+            Err(PageError::CorruptedPage(_))
+        ));
+        // The free space ends past the end of the page
+        writer.write().write_at(4, &u16::MAX.to_be_bytes());
+        assert!(matches!(
+            writer.as_slotted(),
+            Err(PageError::CorruptedPage(_))
+        ));
+        // The slot array runs into the tuples
+        writer.write().write_at(2, &u16::MAX.to_be_bytes());
+        writer
+            .write()
+            .write_at(4, &(PAGE_SIZE as u16).to_be_bytes());
+        assert!(matches!(
+            writer.as_slotted(),
+            Err(PageError::CorruptedPage(_))
+        ));
+    }
+
+    #[test]
+    fn test_btree_page_views() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+
+        let writer = pool.get_page_write(1).unwrap();
+        writer.init_page(PageType::BTreeLeaf);
+        {
+            let mut leaf = writer.as_btree_leaf().unwrap();
+            assert_eq!(leaf.next_leaf(), INVALID_PAGE_ID);
+            leaf.set_entry(0, 10, 100);
+            leaf.set_entry(1, 20, 200);
+            leaf.set_len(2);
+            leaf.set_next_leaf(2);
+        }
+
+        let reader = writer.downgrade();
+        let leaf = reader.as_btree_leaf().unwrap();
+        assert_eq!(leaf.len(), 2);
+        assert_eq!((leaf.key_at(1), leaf.value_at(1)), (20, 200));
+        assert_eq!(leaf.next_leaf(), 2);
+        drop(leaf);
+        assert!(reader.as_btree_internal().is_err());
+    }
+
+    #[test]
+    fn test_corrupted_btree_pages() {
+        setup_logger();
+        let pool = BufferPool::new(4, DiskManager::new(MemoryBackend::new()));
+        let writer = pool.get_page_write(0).unwrap();
+
+        // The number of entries is inflated past what the page can hold
+        writer.init_page(PageType::BTreeLeaf);
+        writer.write().write_at(2, &u16::MAX.to_be_bytes());
+        assert!(matches!(
+            writer.as_btree_leaf(),
+            Err(PageError::CorruptedPage(_))
+        ));
+        writer.init_page(PageType::BTreeInternal);
+        writer.write().write_at(2, &u16::MAX.to_be_bytes());
+        assert!(matches!(
+            writer.as_btree_internal(),
+            Err(PageError::CorruptedPage(_))
+        ));
+
+        // A full page is still valid
+        let capacity = (PAGE_SIZE - 16) / 16;
+        writer.write().write_at(2, &(capacity as u16).to_be_bytes());
+        assert_eq!(writer.as_btree_internal().unwrap().len(), capacity);
+    }
+
+    #[test]
+    fn test_try_read_validation() {
+        setup_logger();
+        let pool = BufferPool::new(
+            4,
+            DiskManager::new(MemoryBackend::from(vec![7u8; PAGE_SIZE])),
+        );
+
+        let try_read = pool.get_page_try_read(0).unwrap();
+        assert_eq!(try_read.read(|frame| frame.data[0]).unwrap(), 7);
+        assert!(try_read.validate());
+
+        {
+            let writer = pool.get_page_write(0).unwrap();
+            let mut frame = writer.write();
+            // A latched frame fails the validation instead of blocking
+            assert!(!try_read.validate());
+            drop(frame);
+            // Releasing the latch without changes keeps the version
+            assert!(try_read.validate());
+
+            frame = writer.write();
+            frame.write_at(0, &[8]);
+        }
+
+        assert!(!try_read.validate());
+        assert!(matches!(
+            try_read.read(|frame| frame.data[0]),
+            Err(BufferPoolError::PageVersionChanged)
+        ));
+
+        let try_read = pool.get_page_try_read(0).unwrap();
+        assert!(try_read.validate());
+    }
+
+    #[test]
+    fn test_read_errors_are_surfaced() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.injector().fail_nth_read(1);
+        backend.injector().fail_nth_read(2);
+        let pool = BufferPool::new(4, DiskManager::new(backend));
+
+        assert!(matches!(
+            pool.get_page_read(0),
+            Err(BufferPoolError::SchedulerError(ScheduleError::IOError(_)))
+        ));
+        assert!(pool.is_empty());
+        // The scheduler survived the error
+        assert!(matches!(
+            pool.get_page_write(1),
+            Err(BufferPoolError::SchedulerError(ScheduleError::IOError(_)))
+        ));
+        assert!(pool.get_page_read(0).is_ok());
+    }
+
+    #[test]
+    fn test_failed_flush_keeps_pages_dirty() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let backend = FaultInjectionBackend::new(disk.clone());
+        let injector = backend.injector();
+        let pool = BufferPool::new(4, DiskManager::new(backend));
+
+        // Pages far apart so their writes are not merged
+        for page_id in [0, 2] {
+            pool.get_page_write(page_id)
+                .unwrap()
+                .write()
+                .write_at(0, &[page_id as u8 + 1]);
+        }
+        injector.fail_nth_write(1);
+        let n_writes = injector.n_writes();
+        assert!(matches!(
+            pool.flush_all_pages(),
+            Err(BufferPoolError::SchedulerError(ScheduleError::IOError(_)))
+        ));
+        assert_eq!(injector.n_writes(), n_writes + 2);
+
+        // Only the page that failed is written again
+        pool.flush_all_pages().unwrap();
+        assert_eq!(injector.n_writes(), n_writes + 3);
+        pool.flush_all_pages().unwrap();
+        assert_eq!(injector.n_writes(), n_writes + 3);
+    }
+
+    #[test]
+    fn test_full_disk_is_reported_as_out_of_space() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.injector().set_capacity(6);
+        let pool = BufferPool::new(4, DiskManager::with_extent_pages(backend, 4));
+
+        // The first extent fits, the second one doesn't
+        assert!(pool.get_page_read(3).is_ok());
+        assert!(matches!(
+            pool.get_page_read(4),
+            Err(BufferPoolError::SchedulerError(ScheduleError::OutOfSpace))
+        ));
+        assert_eq!(pool.disk_manager().allocated_pages(0), Some(4));
+    }
+
+    #[test]
+    fn test_backup_copies_pages_as_they_were_when_it_started() {
+        setup_logger();
+        let pool = BufferPool::new(8, DiskManager::with_extent_pages(MemoryBackend::new(), 4));
+        for page_id in 0..4 {
+            pool.get_page_write(page_id)
+                .unwrap()
+                .write()
+                .write_at(0, &[1]);
+        }
+        // Guards taken before the backup started also copy the pages they modify
+        let mut early_writer = Some(pool.get_page_write(2).unwrap());
+
+        let target = Arc::new(MemoryBackend::new());
+        let mut progress = Vec::new();
+        pool.backup(0, Box::new(target.clone()), |step| {
+            if step.n_copied_pages == 1 {
+                early_writer.take().unwrap().write().write_at(0, &[2]);
+                pool.get_page_write(3).unwrap().write().write_at(0, &[2]);
+            }
+            progress.push(step);
+        })
+        .unwrap();
+
+        // Pages copied by writers are counted once the backup writes them
+        let n_copied_pages: Vec<_> = progress.iter().map(|step| step.n_copied_pages).collect();
+        assert_eq!(n_copied_pages, vec![1, 4, 4, 4]);
+        assert!(progress.iter().all(|step| step.n_pages == 4));
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        for page_no in 0..4 {
+            target.read_page(page_no, &mut buffer).unwrap();
+            assert_eq!(buffer[0], 1);
+        }
+        assert_eq!(pool.get_page_read(2).unwrap().read().data[0], 2);
+
+        // The snapshot is gone, writers don't copy pages anymore
+        pool.get_page_write(0).unwrap().write().write_at(0, &[3]);
+        target.read_page(0, &mut buffer).unwrap();
+        assert_eq!(buffer[0], 1);
+    }
+
+    #[test]
+    fn test_backup_leaves_the_buffer_pool_alone() {
+        setup_logger();
+        let pool = BufferPool::new(2, DiskManager::with_extent_pages(MemoryBackend::new(), 1));
+        for page_id in 0..6 {
+            pool.get_page_write(page_id)
+                .unwrap()
+                .write()
+                .write_at(0, &[page_id as u8 + 1]);
+        }
+        pool.flush_all_pages().unwrap();
+        // Every frame is pinned, and one of them holds a modification not flushed yet
+        let first = pool.get_page_read(0).unwrap();
+        let last = pool.get_page_write(5).unwrap();
+        last.write().write_at(0, &[42]);
+        let resident_pages = || -> std::collections::HashSet<PageId> {
+            pool.page_table.read().unwrap().keys().copied().collect()
+        };
+        let resident = resident_pages();
+
+        let target = Arc::new(MemoryBackend::new());
+        pool.backup(0, Box::new(target.clone()), |_| {}).unwrap();
+
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        for page_no in 0..6 {
+            target.read_page(page_no, &mut buffer).unwrap();
+            let expected = if page_no == 5 { 42 } else { page_no as u8 + 1 };
+            assert_eq!(buffer[0], expected);
+        }
+        assert_eq!(resident_pages(), resident);
+        drop((first, last));
+    }
+
+    #[test]
+    fn test_backup_never_copies_half_modified_pages() {
+        setup_logger();
+        let pool = BufferPool::new(8, DiskManager::with_extent_pages(MemoryBackend::new(), 1));
+        let writer = pool.get_page_write(0).unwrap();
+        let target = Arc::new(MemoryBackend::new());
+
+        std::thread::scope(|scope| {
+            let mut latch = writer.write();
+            latch.write_at(0, &[1]);
+            let backup = scope.spawn(|| pool.backup(0, Box::new(target.clone()), |_| {}));
+            while pool.snapshots.active().is_none() {
+                std::thread::yield_now();
+            }
+            // The latch was taken before the backup started, so the backup waits for it
+            latch.write_at(1, &[1]);
+            drop(latch);
+            backup.join().unwrap().unwrap();
+        });
+
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        target.read_page(0, &mut buffer).unwrap();
+        assert_eq!(buffer[..2], [1, 1]);
+    }
+
+    #[test]
+    fn test_free_list() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let pool = BufferPool::new(8, DiskManager::new(disk.clone()));
+        pool.get_page_write(0).unwrap().init_page(PageType::Header);
+        let pages: Vec<_> = (0..3)
+            .map(|_| pool.allocate_page(0, PageType::Slotted).unwrap().page_id())
+            .collect();
+        assert_eq!(pages, [1, 2, 3]);
+        pool.free_page(2).unwrap();
+        // Freeing a free page does nothing
+        pool.free_page(2).unwrap();
+        pool.free_page(1).unwrap();
+        let header = pool.get_page_read(0).unwrap();
+        assert_eq!(header.as_header().unwrap().free_list_head(), 1);
+        assert_eq!(header.as_header().unwrap().n_free_pages(), 2);
+        drop(header);
+        pool.flush_all_pages().unwrap();
+        drop(pool);
+
+        // The free list is in the data file, and free pages are handed out first
+        let pool = BufferPool::new(8, DiskManager::new(disk));
+        let page = pool.allocate_page(0, PageType::BTreeLeaf).unwrap();
+        assert_eq!(page.page_id(), 1);
+        assert!(page.as_btree_leaf().unwrap().is_empty());
+        drop(page);
+        let pages: Vec<_> = (0..2)
+            .map(|_| pool.allocate_page(0, PageType::Slotted).unwrap().page_id())
+            .collect();
+        assert_eq!(pages, [2, 4]);
+
+        // A free page overwritten without being allocated breaks the list, which is dropped
+        pool.free_page(3).unwrap();
+        pool.free_page(4).unwrap();
+        pool.get_page_write(4).unwrap().init_page(PageType::Slotted);
+        let page = pool.allocate_page(0, PageType::Slotted).unwrap();
+        assert_eq!(page.page_id(), 5);
+        drop(page);
+        let header = pool.get_page_read(0).unwrap();
+        assert_eq!(header.as_header().unwrap().free_list_head(), 0);
+        assert_eq!(header.as_header().unwrap().n_free_pages(), 0);
+    }
+
+    #[test]
+    fn test_truncate_file() {
+        setup_logger();
+        let pool = BufferPool::new(8, DiskManager::with_extent_pages(MemoryBackend::new(), 8));
+        for page_id in 0..4 {
+            pool.get_page_write(page_id)
+                .unwrap()
+                .write()
+                .write_at(100, &[1]);
+        }
+        pool.flush_all_pages().unwrap();
+        assert_eq!(pool.truncate_file(0, 4).unwrap(), 4);
+        assert_eq!(pool.disk_manager().allocated_pages(0), Some(4));
+
+        // Modified pages are kept, along with the pages before them
+        pool.get_page_write(3).unwrap().write().write_at(100, &[2]);
+        assert_eq!(pool.truncate_file(0, 2).unwrap(), 0);
+        pool.flush_all_pages().unwrap();
+
+        // Pinned pages are kept too
+        let reader = pool.get_page_read(2).unwrap();
+        assert_eq!(pool.truncate_file(0, 2).unwrap(), 1);
+        assert_eq!(pool.disk_manager().allocated_pages(0), Some(3));
+        // The frame of page 3 was emptied
+        assert_eq!(pool.len(), 3);
+        drop(reader);
+        assert_eq!(pool.truncate_file(0, 2).unwrap(), 1);
+        assert_eq!(pool.get_page_read(2).unwrap().read().data[100], 0);
+        assert!(pool.truncate_file(1, 0).is_err());
+    }
+
+    #[test]
+    fn test_durability_levels() {
+        setup_logger();
+        for (durability, unsynced_after_write, unsynced_after_flush) in [
+            (Durability::Off, 1, 2),
+            (Durability::Normal, 1, 0),
+            (Durability::Full, 0, 0),
+        ] {
+            let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+            let injector = backend.injector();
+            let mut disk_manager = DiskManager::with_extent_pages(backend, 4);
+            disk_manager.set_durability(durability);
+            let pool = BufferPool::new(1, disk_manager);
+
+            pool.get_page_write(0).unwrap().write().write_at(0, &[1]);
+            // Evicting the page writes it
+            pool.get_page_write(1).unwrap().write().write_at(0, &[1]);
+            assert_eq!(
+                injector.n_unsynced_pages(),
+                unsynced_after_write,
+                "{durability:?}"
+            );
+            pool.flush_all_pages().unwrap();
+            assert_eq!(
+                injector.n_unsynced_pages(),
+                unsynced_after_flush,
+                "{durability:?}"
+            );
+        }
+    }
+}
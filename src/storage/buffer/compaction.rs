@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::buffer_pool::BufferPool;
+use crate::errors::BufferPoolError;
+use crate::storage::page::{page_links, page_type, set_link, FreePage, HeaderPage};
+use crate::storage::{FileId, PageAddress, PageId, PageNo, PageType};
+
+/// What a page of a data file holds, as far as compaction is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    /// Formatted as a free page, in the free list or lost from a broken one
+    Free,
+    /// Never formatted. Dropping it loses nothing.
+    Empty,
+    /// A B+tree node, which can be moved if other pages link to it
+    BTreeNode,
+    /// Anything else, including the header. It never moves.
+    Fixed,
+}
+
+/// The pages of a data file, and how many of them are kept
+struct DataFileSlots {
+    file_id: FileId,
+    slots: Vec<Slot>,
+    n_kept_pages: usize,
+}
+
+/// Compacts every data file and gives the free pages at their end back to the file system,
+/// including the extents preallocated when the files grew. Returns how many pages were released.
+///
+/// The B+tree nodes at the end of a data file are moved to free pages before them, and the links
+/// to them, found with `page_links`, are rewritten. Only the nodes other pages link to are moved,
+/// a node nothing links to may be the root of a tree known by its page id alone. Other pages
+/// never move. The buffer pool must not be used while it runs.
+///
+/// Each step is flushed before the next one, so a crash in the middle loses nothing. Pages are
+/// copied before any link to them is rewritten, and their old location is only freed once every
+/// link is. A free list left broken is dropped by `allocate_page`, and its pages are found again
+/// by the next compaction.
+pub fn compact(pool: &BufferPool) -> Result<u64, BufferPoolError> {
+    if pool.disk_manager().is_read_only() {
+        return Err(BufferPoolError::ReadOnly);
+    }
+    pool.flush_all_pages()?;
+
+    // The pages linking to each page
+    let mut referrers: HashMap<PageId, Vec<PageId>> = HashMap::new();
+    let mut files = Vec::new();
+    for file_id in pool.disk_manager().file_ids() {
+        files.push(DataFileSlots::scan(pool, file_id, &mut referrers)?);
+    }
+    let mut moves = BTreeMap::new();
+    for file in &mut files {
+        file.plan_moves(&referrers, &mut moves);
+    }
+    log::debug!("Compacting the data files, moving {} pages", moves.len());
+
+    for (&from, &to) in &moves {
+        let data = pool.get_page_read(from)?.read().data.to_vec();
+        pool.get_page_write(to)?.write().write_at(0, &data);
+    }
+    pool.flush_all_pages()?;
+
+    // A moved page linking to another moved page is rewritten at its new location
+    let referrers: BTreeSet<PageId> = moves
+        .keys()
+        .flat_map(|moved| &referrers[moved])
+        .map(|referrer| *moves.get(referrer).unwrap_or(referrer))
+        .collect();
+    for referrer in referrers {
+        let page = pool.get_page_write(referrer)?;
+        let mut latch = page.write();
+        for link in page_links(&latch)? {
+            if let Some(&to) = moves.get(&link.page_id) {
+                set_link(&mut latch, link, to);
+            }
+        }
+    }
+    pool.flush_all_pages()?;
+
+    for file in &files {
+        file.rebuild_free_list(pool)?;
+    }
+    pool.flush_all_pages()?;
+
+    let mut n_released_pages = 0;
+    for file in &files {
+        n_released_pages += pool.truncate_file(file.file_id, file.n_kept_pages as u64)?;
+    }
+    Ok(n_released_pages)
+}
+
+impl DataFileSlots {
+    /// Reads every page of the data file, recording the pages each page links to
+    fn scan(
+        pool: &BufferPool,
+        file_id: FileId,
+        referrers: &mut HashMap<PageId, Vec<PageId>>,
+    ) -> Result<Self, BufferPoolError> {
+        let n_pages = pool.disk_manager().allocated_pages(file_id).unwrap_or(0) as usize;
+        let mut slots = Vec::with_capacity(n_pages);
+        for page_no in 0..n_pages.max(1) {
+            let page_id = PageAddress::new(file_id, page_no as PageNo).page_id();
+            let page = pool.get_page_read(page_id)?;
+            let data = page.read();
+            if page_no == 0 {
+                // Fails if the data file has no header to record its free list in
+                HeaderPage::new(&data.data[..])?;
+            }
+            // The links of a damaged page can't be rewritten, so compaction stops there
+            for link in page_links(&data)? {
+                referrers.entry(link.page_id).or_default().push(page_id);
+            }
+            slots.push(match page_type(&data)? {
+                PageType::Free if FreePage::new(&data.data[..], page_id).is_ok() => Slot::Free,
+                PageType::Free if data.data.iter().all(|byte| *byte == 0) => Slot::Empty,
+                PageType::BTreeInternal | PageType::BTreeLeaf => Slot::BTreeNode,
+                _ => Slot::Fixed,
+            });
+        }
+        Ok(DataFileSlots {
+            file_id,
+            n_kept_pages: slots.len(),
+            slots,
+        })
+    }
+
+    /// Moves the linked B+tree nodes at the end of the file to the first free pages, as long as
+    /// there are free pages before them, and drops the free and empty pages left at the end
+    fn plan_moves(
+        &mut self,
+        referrers: &HashMap<PageId, Vec<PageId>>,
+        moves: &mut BTreeMap<PageId, PageId>,
+    ) {
+        let page_id = |page_no: usize| PageAddress::new(self.file_id, page_no as PageNo).page_id();
+        let mut free_pages: BTreeSet<usize> = (0..self.slots.len())
+            .filter(|page_no| self.slots[*page_no] == Slot::Free)
+            .collect();
+        loop {
+            while matches!(self.slots[self.n_kept_pages - 1], Slot::Free | Slot::Empty) {
+                self.n_kept_pages -= 1;
+                free_pages.remove(&self.n_kept_pages);
+            }
+            let last = self.n_kept_pages - 1;
+            if self.slots[last] != Slot::BTreeNode || !referrers.contains_key(&page_id(last)) {
+                break;
+            }
+            let Some(free_page) = free_pages.pop_first() else {
+                break;
+            };
+            moves.insert(page_id(last), page_id(free_page));
+            self.slots[free_page] = Slot::BTreeNode;
+            self.slots[last] = Slot::Free;
+        }
+    }
+
+    /// Chains the free pages that are kept from the header, in increasing order so pages are
+    /// reused from the start of the file
+    fn rebuild_free_list(&self, pool: &BufferPool) -> Result<(), BufferPoolError> {
+        let free_pages: Vec<_> = (1..self.n_kept_pages)
+            .filter(|page_no| self.slots[*page_no] == Slot::Free)
+            .map(|page_no| page_no as PageNo)
+            .collect();
+        let header_page = pool.get_page_write(PageAddress::new(self.file_id, 0).page_id())?;
+        let mut header = header_page.as_header()?;
+        let mut next = 0;
+        for &page_no in free_pages.iter().rev() {
+            let page_id = PageAddress::new(self.file_id, page_no).page_id();
+            FreePage::init(pool.get_page_write(page_id)?.write(), page_id, next);
+            next = page_no;
+        }
+        header.set_free_list(next, free_pages.len() as u32);
+        header.set_n_pages(header.n_pages().min(self.n_kept_pages as u32));
+        Ok(())
+    }
+}
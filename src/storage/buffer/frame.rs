@@ -1,18 +1,27 @@
-use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
 
 use super::eviction::EvictionPolicy;
-use crate::storage::PageId;
+use super::pin_tracker::PinToken;
+use super::snapshot::SnapshotSlot;
+use crate::errors::{BufferPoolError, PageError};
+use crate::storage::page::{
+    self, reset_page, BTreeInternalPage, BTreeLeafPage, HeaderPage, SlottedPage,
+};
+use crate::storage::{PageAddress, PageId, PageType};
 
 /// The Buffer Pool frame id for internal use only. It is not associated with the page id.
 pub type FrameId = u16;
 
 #[derive(Debug)]
 pub struct Frame {
-    /// How many threads are accessing this page. A page can only be evicted if pin_count is 0.
     pub page_id: Option<PageId>,
-    pub pin_count: u32,
     pub is_dirty: bool,
-    /// Heap allocated frame of size PAGE_SIZE.
+    /// Incremented every time the frame data is modified or loaded with another page.
+    /// Non-blocking readers compare it with the version they started with to detect changes.
+    pub version: u64,
+    /// Heap allocated frame of the page size of the database.
     /// It is only guaranteed to contain valid page data if page_metadata is Some.
     pub data: Box<[u8]>,
 }
@@ -20,96 +29,437 @@ pub struct Frame {
 impl Frame {
     pub fn new(data: Box<[u8]>) -> Self {
         Frame {
-            pin_count: 0,
             page_id: None,
             is_dirty: false,
+            version: 0,
             data,
         }
     }
 }
 
-/// Wrapper for a RwLockReadGuard that decrements the frame pin count
-pub struct PageReadGuard {
+/// How many guards are accessing each frame. A frame can only be evicted if its count is 0.
+///
+/// The counts live outside of the frames so pinning never waits for the frame latch. They are
+/// changed under a lock together with the evictable flag of the frame, so the eviction policy
+/// never sees a pinned frame as evictable.
+pub(crate) struct PinCounts {
+    counts: Mutex<Vec<u32>>,
+    eviction_policy: Arc<dyn EvictionPolicy + Send + Sync>,
+}
+
+impl PinCounts {
+    pub fn new(n_frames: usize, eviction_policy: Arc<dyn EvictionPolicy + Send + Sync>) -> Self {
+        PinCounts {
+            counts: Mutex::new(vec![0; n_frames]),
+            eviction_policy,
+        }
+    }
+
+    /// The number of guards pinning the frame
+    pub fn get(&self, frame_id: FrameId) -> u32 {
+        self.counts()[frame_id as usize]
+    }
+
+    /// Follows the number of frames of the buffer pool. Dropped frames must be unpinned.
+    pub fn resize(&self, n_frames: usize) {
+        self.counts().resize(n_frames, 0);
+    }
+
+    fn pin(&self, frame_id: FrameId) {
+        let mut counts = self.counts();
+        // Acknowledge the page access to the eviction policy
+        self.eviction_policy
+            .record_access(frame_id, super::eviction::AccessType::Lookup);
+        self.eviction_policy.set_evictable(frame_id, false);
+        counts[frame_id as usize] += 1;
+    }
+
+    fn unpin(&self, frame_id: FrameId) {
+        let mut counts = self.counts();
+        counts[frame_id as usize] -= 1;
+        if counts[frame_id as usize] == 0 {
+            self.eviction_policy.set_evictable(frame_id, true);
+        }
+    }
+
+    fn counts(&self) -> std::sync::MutexGuard<'_, Vec<u32>> {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keeps a frame pinned for as long as it lives. The page guards are built on top of it so a
+/// pin can be moved from one guard kind to another without releasing it in between. Pinning
+/// doesn't latch the frame: the buffer pool pins under the page table lock, so the frame can't
+/// be evicted, and latches once the page table is released.
+pub(crate) struct FramePin {
     frame_id: FrameId,
     frame: Arc<RwLock<Frame>>,
-    eviction_policy: Arc<dyn EvictionPolicy>,
+    pin_counts: Arc<PinCounts>,
+    /// Registration in the pin leak detector. Only present in debug builds.
+    pin_token: Option<PinToken>,
+    /// Where writers find the snapshot that must get the page before it is modified
+    snapshots: Option<Arc<SnapshotSlot>>,
+    /// Read guards can't be upgraded when the buffer pool is read-only
+    read_only: bool,
+}
+
+impl FramePin {
+    pub fn new(frame_id: FrameId, frame: Arc<RwLock<Frame>>, pin_counts: Arc<PinCounts>) -> Self {
+        pin_counts.pin(frame_id);
+        FramePin {
+            frame_id,
+            frame,
+            pin_counts,
+            pin_token: None,
+            snapshots: None,
+            read_only: false,
+        }
+    }
+
+    /// Guards are only handed out once their frame holds the page they asked for
+    fn page_id(&self) -> PageId {
+        self.frame
+            .read()
+            .unwrap()
+            .page_id
+            .expect("Pinned frames hold a page")
+    }
+
+    pub fn set_pin_token(&mut self, pin_token: Option<PinToken>) {
+        self.pin_token = pin_token;
+    }
+
+    pub fn set_snapshots(&mut self, snapshots: Arc<SnapshotSlot>) {
+        self.snapshots = Some(snapshots);
+    }
+
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    fn version(&self) -> u64 {
+        self.frame
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .version
+    }
+}
+
+impl Drop for FramePin {
+    fn drop(&mut self) {
+        self.pin_counts.unpin(self.frame_id);
+    }
+}
+
+/// Wrapper for a RwLockReadGuard that decrements the frame pin count
+pub struct PageReadGuard {
+    pin: FramePin,
+    /// The frame version seen by the last read. Upgrades are only allowed if it didn't change.
+    observed_version: AtomicU64,
 }
 
 /// Wrapper for a RwLockWriteGuard that decrements the frame pin count
 pub struct PageWriteGuard {
-    frame_id: FrameId,
+    pin: FramePin,
+    /// Sorted and non-overlapping byte ranges of the page modified through this guard
+    modified_ranges: Mutex<Vec<Range<usize>>>,
+    /// The frame version checked by `PageReadGuard::try_upgrade`, if the guard comes from one
+    version_at_upgrade: Option<u64>,
+}
+
+/// The frame latched for writing. Reading the frame is always allowed, but the page data can
+/// only be modified through the methods below, so the frame is marked dirty and the modified
+/// bytes are recorded only when there is an actual write.
+pub struct FrameWriteLatch<'a> {
+    frame: RwLockWriteGuard<'a, Frame>,
+    modified_ranges: &'a Mutex<Vec<Range<usize>>>,
+    /// Whether the data was modified during this latch. The version is bumped only once.
+    is_modified: bool,
+}
+
+/// An unpinned handle to a page whose reads never wait for a writer.
+///
+/// Every read takes the shared frame latch with `try_read`, so a frame latched for writing fails
+/// the read right away instead of blocking. The guard remembers the frame version at the moment
+/// it was created and every read checks it is still the same, otherwise the page was modified or
+/// evicted in the meantime and the caller must restart its operation. Reads are never done
+/// without the latch and validated afterwards: the frame data is not atomic, so reading it while
+/// a writer modifies it would be a data race.
+pub struct PageTryReadGuard {
+    page_id: PageId,
     frame: Arc<RwLock<Frame>>,
-    eviction_policy: Arc<dyn EvictionPolicy>,
+    version: u64,
 }
 
 impl PageReadGuard {
-    pub fn new(
-        frame_id: FrameId,
-        frame: Arc<RwLock<Frame>>,
-        eviction_policy: Arc<dyn EvictionPolicy>,
-    ) -> Self {
-        // Acknowledge the page access to the eviction policy
-        eviction_policy.record_access(frame_id, super::eviction::AccessType::Lookup);
-        eviction_policy.set_evictable(frame_id, false);
-        {
-            let mut frame = frame.write().unwrap_or_else(PoisonError::into_inner);
-            frame.pin_count += 1;
-        }
+    /// Latches the pinned frame to record the version it is read at
+    pub(crate) fn from_pin(pin: FramePin) -> Self {
+        let observed_version = AtomicU64::new(pin.version());
         PageReadGuard {
-            frame_id,
-            frame,
-            eviction_policy,
+            pin,
+            observed_version,
         }
     }
 
+    pub fn frame_id(&self) -> FrameId {
+        self.pin.frame_id
+    }
+
+    /// The page held by the frame
+    pub fn page_id(&self) -> PageId {
+        self.pin.page_id()
+    }
+
     pub fn read(&self) -> RwLockReadGuard<'_, Frame> {
-        self.frame.read().unwrap()
+        let frame = self.pin.frame.read().unwrap();
+        self.observed_version
+            .store(frame.version, Ordering::Release);
+        frame
+    }
+
+    /// The type the page is tagged with
+    pub fn page_type(&self) -> Result<PageType, PageError> {
+        page::page_type(&self.read())
+    }
+
+    pub fn as_header(&self) -> Result<HeaderPage<RwLockReadGuard<'_, Frame>>, PageError> {
+        HeaderPage::new(self.read())
+    }
+
+    pub fn as_slotted(&self) -> Result<SlottedPage<RwLockReadGuard<'_, Frame>>, PageError> {
+        SlottedPage::new(self.read())
+    }
+
+    pub fn as_btree_internal(
+        &self,
+    ) -> Result<BTreeInternalPage<RwLockReadGuard<'_, Frame>>, PageError> {
+        BTreeInternalPage::new(self.read())
+    }
+
+    pub fn as_btree_leaf(&self) -> Result<BTreeLeafPage<RwLockReadGuard<'_, Frame>>, PageError> {
+        BTreeLeafPage::new(self.read())
+    }
+
+    /// Turns this guard into a write guard while keeping the frame pinned, so the page can't be
+    /// evicted in between. It fails, handing the read guard back, if the page was written since
+    /// it was last read through this guard, because the decision to modify it may be stale. It
+    /// always fails if the buffer pool is read-only.
+    ///
+    /// The version is only checked at the upgrade. Like any write guard, the returned guard is
+    /// exclusive only while one of its latches is held, so another write guard of the page can
+    /// modify it before the first `write`. Callers that can't afford that compare the version of
+    /// the latched frame with `version_at_upgrade`.
+    pub fn try_upgrade(self) -> Result<PageWriteGuard, PageReadGuard> {
+        let version = self.observed_version.load(Ordering::Acquire);
+        if !self.pin.read_only && self.pin.version() == version {
+            let mut guard = PageWriteGuard::from_pin(self.pin);
+            guard.version_at_upgrade = Some(version);
+            Ok(guard)
+        } else {
+            Err(self)
+        }
     }
 }
 
 impl PageWriteGuard {
-    pub fn new(
-        frame_id: FrameId,
-        frame: Arc<RwLock<Frame>>,
-        eviction_policy: Arc<dyn EvictionPolicy>,
-    ) -> Self {
-        // Acknowledge the page access to the eviction policy
-        eviction_policy.record_access(frame_id, super::eviction::AccessType::Lookup);
-        eviction_policy.set_evictable(frame_id, false);
-        {
-            let mut frame = frame.write().unwrap_or_else(PoisonError::into_inner);
-            frame.pin_count += 1;
-            frame.is_dirty = true;
-        }
+    pub(crate) fn from_pin(pin: FramePin) -> Self {
         PageWriteGuard {
-            frame_id,
+            pin,
+            modified_ranges: Mutex::new(Vec::new()),
+            version_at_upgrade: None,
+        }
+    }
+
+    /// The frame version when this guard was upgraded from a read guard. A latch seeing another
+    /// version means the page was written by someone else after the upgrade.
+    pub fn version_at_upgrade(&self) -> Option<u64> {
+        self.version_at_upgrade
+    }
+
+    pub fn frame_id(&self) -> FrameId {
+        self.pin.frame_id
+    }
+
+    /// The page held by the frame
+    pub fn page_id(&self) -> PageId {
+        self.pin.page_id()
+    }
+
+    pub fn write(&self) -> FrameWriteLatch<'_> {
+        let frame = self.pin.frame.write().unwrap();
+        // A snapshot being taken must get the page as it was before this latch modifies it
+        let snapshot = self.pin.snapshots.as_deref().and_then(SnapshotSlot::active);
+        if let (Some(snapshot), Some(page_id)) = (snapshot, frame.page_id) {
+            snapshot.copy_page(page_id, &frame.data);
+        }
+        FrameWriteLatch {
             frame,
-            eviction_policy,
+            modified_ranges: &self.modified_ranges,
+            is_modified: false,
+        }
+    }
+
+    /// The type the page is tagged with
+    pub fn page_type(&self) -> Result<PageType, PageError> {
+        page::page_type(&self.write())
+    }
+
+    pub fn as_header(&self) -> Result<HeaderPage<FrameWriteLatch<'_>>, PageError> {
+        HeaderPage::new(self.write())
+    }
+
+    pub fn as_slotted(&self) -> Result<SlottedPage<FrameWriteLatch<'_>>, PageError> {
+        SlottedPage::new(self.write())
+    }
+
+    pub fn as_btree_internal(&self) -> Result<BTreeInternalPage<FrameWriteLatch<'_>>, PageError> {
+        BTreeInternalPage::new(self.write())
+    }
+
+    pub fn as_btree_leaf(&self) -> Result<BTreeLeafPage<FrameWriteLatch<'_>>, PageError> {
+        BTreeLeafPage::new(self.write())
+    }
+
+    /// Formats the page as an empty page of the given type, discarding its previous contents
+    pub fn init_page(&self, page_type: PageType) {
+        let mut frame = self.write();
+        match page_type {
+            PageType::Free => reset_page(&mut frame, PageType::Free),
+            PageType::Header => {
+                let file_id = frame
+                    .page_id
+                    .map_or(0, |page_id| PageAddress::of(page_id).file_id);
+                drop(HeaderPage::init(frame, file_id))
+            }
+            PageType::Slotted => drop(SlottedPage::init(frame)),
+            PageType::BTreeInternal => drop(BTreeInternalPage::init(frame)),
+            PageType::BTreeLeaf => drop(BTreeLeafPage::init(frame)),
         }
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<Frame> {
-        self.frame.write().unwrap()
+    /// The byte ranges of the page modified through this guard, sorted and merged.
+    /// Together with the frame data, they describe the after image of the changes.
+    pub fn modified_ranges(&self) -> Vec<Range<usize>> {
+        self.modified_ranges
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Turns this guard into a read guard. The frame stays pinned during the conversion.
+    pub fn downgrade(self) -> PageReadGuard {
+        let observed_version = AtomicU64::new(self.pin.version());
+        PageReadGuard {
+            pin: self.pin,
+            observed_version,
+        }
     }
 }
 
-impl Drop for PageWriteGuard {
-    fn drop(&mut self) {
-        let mut frame = self.frame.write().unwrap_or_else(PoisonError::into_inner);
-        frame.pin_count -= 1;
-        if frame.pin_count == 0 {
-            self.eviction_policy.set_evictable(self.frame_id, true);
-            // TODO: flush to disk?
+impl FrameWriteLatch<'_> {
+    /// Mutable access to the whole page. The whole page is considered modified.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let len = self.frame.data.len();
+        self.range_mut(0..len)
+    }
+
+    /// Mutable access to a range of the page. Panics if the range is out of bounds.
+    pub fn range_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        assert!(
+            range.start <= range.end && range.end <= self.frame.data.len(),
+            "Range {range:?} out of page bounds",
+        );
+        self.mark_modified(range.clone());
+        &mut self.frame.data[range]
+    }
+
+    /// Copies `bytes` into the page starting at `offset`
+    pub fn write_at(&mut self, offset: usize, bytes: &[u8]) {
+        self.range_mut(offset..offset + bytes.len())
+            .copy_from_slice(bytes);
+    }
+
+    fn mark_modified(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
         }
+        if !self.is_modified {
+            self.is_modified = true;
+            self.frame.is_dirty = true;
+            self.frame.version += 1;
+        }
+
+        let mut ranges = self
+            .modified_ranges
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        insert_range(&mut ranges, range);
     }
 }
 
-impl Drop for PageReadGuard {
-    fn drop(&mut self) {
-        let mut frame = self.frame.write().unwrap_or_else(PoisonError::into_inner);
-        frame.pin_count -= 1;
-        if frame.pin_count == 0 {
-            self.eviction_policy.set_evictable(self.frame_id, true);
+impl Deref for FrameWriteLatch<'_> {
+    type Target = Frame;
+
+    fn deref(&self) -> &Frame {
+        &self.frame
+    }
+}
+
+/// Inserts a range into a sorted list of non-overlapping ranges, merging the ranges it overlaps
+/// or touches.
+fn insert_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    let mut merged = range;
+    ranges.retain(|other| {
+        let overlaps = other.start <= merged.end && merged.start <= other.end;
+        if overlaps {
+            merged = merged.start.min(other.start)..merged.end.max(other.end);
+        }
+        !overlaps
+    });
+    let position = ranges.partition_point(|other| other.start < merged.start);
+    ranges.insert(position, merged);
+}
+
+impl PageTryReadGuard {
+    pub fn new(page_id: PageId, frame: Arc<RwLock<Frame>>, version: u64) -> Self {
+        PageTryReadGuard {
+            page_id,
+            frame,
+            version,
+        }
+    }
+
+    /// The frame version this guard was created with
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Runs `f` over the frame, under the shared latch, if the page hasn't changed since this
+    /// guard was created. It never waits for a writer: a latched frame fails right away.
+    pub fn read<T>(&self, f: impl FnOnce(&Frame) -> T) -> Result<T, BufferPoolError> {
+        let frame = match self.frame.try_read() {
+            Ok(frame) => frame,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(BufferPoolError::PageVersionChanged),
+        };
+
+        if !self.is_valid(&frame) {
+            return Err(BufferPoolError::PageVersionChanged);
+        }
+        Ok(f(&frame))
+    }
+
+    /// Whether the page is still the same as when this guard was created. False while a writer
+    /// holds the frame latch.
+    pub fn validate(&self) -> bool {
+        match self.frame.try_read() {
+            Ok(frame) => self.is_valid(&frame),
+            Err(TryLockError::Poisoned(err)) => self.is_valid(&err.into_inner()),
+            Err(TryLockError::WouldBlock) => false,
         }
     }
+
+    fn is_valid(&self, frame: &Frame) -> bool {
+        frame.version == self.version && frame.page_id == Some(self.page_id)
+    }
 }
@@ -156,6 +156,7 @@ impl EvictionPolicy for LRUKEvictionPolicy {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use crate::shared::logger::setup_logger;
 
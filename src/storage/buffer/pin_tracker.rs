@@ -0,0 +1,165 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::frame::FrameId;
+use crate::storage::PageId;
+
+/// Keeps track of every live page guard in debug builds, so a forgotten guard can be traced back
+/// to the code that created it. In release builds nothing is recorded.
+pub struct PinTracker {
+    next_pin_id: AtomicU64,
+    pins: Mutex<HashMap<u64, PinRecord>>,
+    /// Pins older than this are reported with a warning
+    threshold: Mutex<Duration>,
+}
+
+struct PinRecord {
+    page_id: PageId,
+    frame_id: FrameId,
+    location: &'static Location<'static>,
+    /// Only captured if backtraces are enabled through RUST_BACKTRACE
+    backtrace: Arc<Backtrace>,
+    pinned_at: Instant,
+    warned: bool,
+}
+
+/// Unregisters its pin from the tracker when dropped. It lives as long as the pinning guard.
+pub struct PinToken {
+    pin_id: u64,
+    tracker: Arc<PinTracker>,
+}
+
+/// A page that is currently pinned by a guard
+#[derive(Debug, Clone)]
+pub struct PinnedPage {
+    pub page_id: PageId,
+    pub frame_id: FrameId,
+    /// Where the guard pinning the page was created
+    pub location: &'static Location<'static>,
+    pub pinned_for: Duration,
+    pub backtrace: Arc<Backtrace>,
+}
+
+impl PinTracker {
+    pub fn new(threshold: Duration) -> Self {
+        PinTracker {
+            next_pin_id: AtomicU64::new(0),
+            pins: Mutex::new(HashMap::new()),
+            threshold: Mutex::new(threshold),
+        }
+    }
+
+    /// Whether pins are being tracked. Tracking is only enabled in debug builds.
+    pub fn is_enabled(&self) -> bool {
+        cfg!(debug_assertions)
+    }
+
+    pub fn set_threshold(&self, threshold: Duration) {
+        *self
+            .threshold
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = threshold;
+    }
+
+    /// Records a new pin created at the given location
+    pub fn register(
+        self: &Arc<Self>,
+        page_id: PageId,
+        frame_id: FrameId,
+        location: &'static Location<'static>,
+    ) -> Option<PinToken> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let pin_id = self.next_pin_id.fetch_add(1, Ordering::Relaxed);
+        let record = PinRecord {
+            page_id,
+            frame_id,
+            location,
+            backtrace: Arc::new(Backtrace::capture()),
+            pinned_at: Instant::now(),
+            warned: false,
+        };
+        self.lock_pins().insert(pin_id, record);
+
+        Some(PinToken {
+            pin_id,
+            tracker: self.clone(),
+        })
+    }
+
+    /// Logs a warning for every page pinned for longer than the threshold. Each pin is only
+    /// reported once.
+    pub fn warn_long_pinned_pages(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        let threshold = *self
+            .threshold
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        for record in self.lock_pins().values_mut() {
+            let pinned_for = record.pinned_at.elapsed();
+            if !record.warned && pinned_for > threshold {
+                record.warned = true;
+                log::warn!(
+                    "page_id={} has been pinned for {:?} by a guard created at {}",
+                    record.page_id,
+                    pinned_for,
+                    record.location,
+                );
+            }
+        }
+    }
+
+    /// Every page currently pinned, the oldest pins first. Pins taken at the same instant are in
+    /// the order they were taken.
+    pub fn pinned_pages(&self) -> Vec<PinnedPage> {
+        let pins = self.lock_pins();
+        let mut records: Vec<_> = pins.iter().collect();
+        records.sort_by_key(|(pin_id, record)| (record.pinned_at, **pin_id));
+
+        let now = Instant::now();
+        records
+            .into_iter()
+            .map(|(_, record)| PinnedPage {
+                page_id: record.page_id,
+                frame_id: record.frame_id,
+                location: record.location,
+                pinned_for: now.saturating_duration_since(record.pinned_at),
+                backtrace: record.backtrace.clone(),
+            })
+            .collect()
+    }
+
+    fn lock_pins(&self) -> MutexGuard<'_, HashMap<u64, PinRecord>> {
+        self.pins.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for PinToken {
+    fn drop(&mut self) {
+        self.tracker.lock_pins().remove(&self.pin_id);
+    }
+}
+
+impl fmt::Display for PinnedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "page_id={} frame_id={} pinned for {:?} at {}",
+            self.page_id, self.frame_id, self.pinned_for, self.location
+        )?;
+        if self.backtrace.status() == BacktraceStatus::Captured {
+            write!(f, "\n{}", self.backtrace)?;
+        }
+        Ok(())
+    }
+}
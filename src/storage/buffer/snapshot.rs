@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use crate::storage::{FileId, PageAddress, PageId, PageNo, StorageBackend};
+
+/// A point-in-time copy of a data file, written to another backend while the buffer pool keeps
+/// serving writers. The backup copies the pages in order, and a writer latching a page that was
+/// not copied yet copies it first, so every page is copied as it was when the snapshot started.
+/// Pages are copied in memory while they are latched, only the backup writes them to the target.
+/// A page latched for writing when the snapshot starts is copied with the changes made under that
+/// latch, never with part of them.
+pub struct Snapshot {
+    file_id: FileId,
+    n_pages: u64,
+    target: Box<dyn StorageBackend>,
+    /// Which pages were copied already
+    copied: Mutex<Vec<bool>>,
+    /// Pages copied but not written to the target yet
+    pending: Mutex<Vec<(PageNo, Box<[u8]>)>>,
+    n_copied_pages: AtomicU64,
+}
+
+/// How far a backup is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub n_copied_pages: u64,
+    pub n_pages: u64,
+}
+
+/// Where the buffer pool publishes the snapshot being taken, if any. Every page guard shares it,
+/// so writers also see the snapshots started after they got their guard.
+#[derive(Default)]
+pub(crate) struct SnapshotSlot(RwLock<Option<Arc<Snapshot>>>);
+
+impl Snapshot {
+    pub(crate) fn new(file_id: FileId, n_pages: u64, target: Box<dyn StorageBackend>) -> Self {
+        Snapshot {
+            file_id,
+            n_pages,
+            target,
+            copied: Mutex::new(vec![false; n_pages as usize]),
+            pending: Mutex::new(Vec::new()),
+            n_copied_pages: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn n_pages(&self) -> u64 {
+        self.n_pages
+    }
+
+    pub(crate) fn progress(&self) -> BackupProgress {
+        BackupProgress {
+            n_copied_pages: self.n_copied_pages.load(Ordering::Acquire),
+            n_pages: self.n_pages,
+        }
+    }
+
+    /// Whether the page is part of the snapshot and was copied already
+    pub(crate) fn is_copied(&self, page_id: PageId) -> bool {
+        self.index(page_id)
+            .is_some_and(|index| self.copied.lock().unwrap_or_else(PoisonError::into_inner)[index])
+    }
+
+    /// Copies the page in memory unless it is not part of the snapshot or it was copied already.
+    /// The caller must latch the page so it doesn't change during the copy.
+    pub(crate) fn copy_page(&self, page_id: PageId, data: &[u8]) {
+        let Some(index) = self.index(page_id) else {
+            return;
+        };
+        let mut copied = self.copied.lock().unwrap_or_else(PoisonError::into_inner);
+        if copied[index] {
+            return;
+        }
+        copied[index] = true;
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((PageAddress::of(page_id).page_no, data.into()));
+    }
+
+    /// Writes the pages copied so far to the target. Pages are counted as copied once written.
+    pub(crate) fn write_pending(&self) -> std::io::Result<()> {
+        let pending =
+            std::mem::take(&mut *self.pending.lock().unwrap_or_else(PoisonError::into_inner));
+        for (page_no, data) in pending {
+            self.target.write_page(page_no, &data)?;
+            self.n_copied_pages.fetch_add(1, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+
+    /// Writes the pages left and makes the copy durable
+    pub(crate) fn finish(&self) -> std::io::Result<()> {
+        self.write_pending()?;
+        self.target.sync()
+    }
+
+    fn index(&self, page_id: PageId) -> Option<usize> {
+        let address = PageAddress::of(page_id);
+        (address.file_id == self.file_id && (address.page_no as u64) < self.n_pages)
+            .then_some(address.page_no as usize)
+    }
+}
+
+impl SnapshotSlot {
+    pub(crate) fn active(&self) -> Option<Arc<Snapshot>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Publishes the snapshot. Fails if another one is being taken.
+    pub(crate) fn install(&self, snapshot: Arc<Snapshot>) -> bool {
+        let mut slot = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(snapshot);
+        true
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
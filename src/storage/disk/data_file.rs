@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use super::double_write::{double_write_path, DoubleWriteBackend};
+use super::encryption::{EncryptedBackend, EncryptionKey, ENCRYPTION_TRAILER_SIZE};
+use super::mmap_backend::MmapBackend;
+use super::storage_backend::{FileBackend, StorageBackend};
+use crate::errors::DatabaseError;
+use crate::storage::FileId;
+
+/// The storage backends a data file can be opened with. Other backends, such as
+/// `MemoryBackend`, are built by the caller and given to `DiskManager::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// Positional reads and writes on the file
+    File,
+    /// The file is mapped in memory
+    Mmap,
+}
+
+/// How a data file is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Opens the file, creating it if it doesn't exist. Fails if it is open already.
+    Open,
+    /// Opens an existing file without write permission. Fails if it is open for writing.
+    ReadOnly,
+    /// Creates the file. Fails if it already exists.
+    Create,
+}
+
+/// How the disk manager opens the data files of a database. Every data file of a database is
+/// opened with the same options.
+#[derive(Debug, Clone)]
+pub struct DataFileOptions {
+    pub storage: StorageKind,
+    /// Writes pages to a double-write buffer next to the data file before writing them in place
+    pub double_write: bool,
+    /// Encrypts every page of the data file with this key
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+impl Default for DataFileOptions {
+    fn default() -> Self {
+        DataFileOptions {
+            storage: StorageKind::File,
+            double_write: false,
+            encryption_key: None,
+        }
+    }
+}
+
+impl DataFileOptions {
+    /// Opens the data file at `path` holding pages of `page_size` bytes. The file id is part of
+    /// what encrypts the pages, so a data file must be opened with the same id every time.
+    pub fn open(
+        &self,
+        path: &Path,
+        file_id: FileId,
+        page_size: usize,
+        mode: OpenMode,
+    ) -> Result<Box<dyn StorageBackend>, DatabaseError> {
+        let page_size = self.file_page_size(page_size);
+        let backend: std::io::Result<Box<dyn StorageBackend>> = match (self.storage, mode) {
+            (StorageKind::File, OpenMode::Open) => {
+                FileBackend::open(path).map(|b| Box::new(b.with_page_size(page_size)) as _)
+            }
+            (StorageKind::File, OpenMode::ReadOnly) => FileBackend::open_read_only(path)
+                .map(|b| Box::new(b.with_page_size(page_size)) as _),
+            (StorageKind::File, OpenMode::Create) => {
+                FileBackend::create(path).map(|b| Box::new(b.with_page_size(page_size)) as _)
+            }
+            (StorageKind::Mmap, OpenMode::Open) => {
+                MmapBackend::open(path).map(|b| Box::new(b.with_page_size(page_size)) as _)
+            }
+            (StorageKind::Mmap, OpenMode::ReadOnly) => MmapBackend::open_read_only(path)
+                .map(|b| Box::new(b.with_page_size(page_size)) as _),
+            (StorageKind::Mmap, OpenMode::Create) => {
+                MmapBackend::create(path).map(|b| Box::new(b.with_page_size(page_size)) as _)
+            }
+        };
+        let backend = backend.map_err(|err| match err.kind() {
+            std::io::ErrorKind::AlreadyExists => DatabaseError::AlreadyExists(path.to_path_buf()),
+            std::io::ErrorKind::WouldBlock => DatabaseError::DatabaseLocked(path.to_path_buf()),
+            _ => DatabaseError::IOError(err),
+        })?;
+        // Read-only files are never written, not even to repair torn pages
+        let backend = match mode {
+            OpenMode::ReadOnly => backend,
+            _ => self.double_write(backend, path, mode == OpenMode::Create)?,
+        };
+        Ok(self.encrypt(backend, file_id))
+    }
+
+    /// Encrypts the pages of the backend if there is a key. Its pages must be `file_page_size`
+    /// bytes.
+    pub fn encrypt(
+        &self,
+        backend: impl StorageBackend + 'static,
+        file_id: FileId,
+    ) -> Box<dyn StorageBackend> {
+        match &self.encryption_key {
+            Some(key) => Box::new(EncryptedBackend::new(backend, key, file_id)),
+            None => Box::new(backend),
+        }
+    }
+
+    /// The size of the pages in the data files of a database with pages of `page_size` bytes.
+    /// Each page of an encrypted data file is followed by what is needed to decrypt it.
+    pub fn file_page_size(&self, page_size: usize) -> usize {
+        match self.encryption_key {
+            Some(_) => page_size + ENCRYPTION_TRAILER_SIZE,
+            None => page_size,
+        }
+    }
+
+    /// Writes the pages of the data file at `path` through a double-write buffer if asked to. A
+    /// new data file starts with an empty buffer, a leftover one belongs to a file that was
+    /// deleted.
+    fn double_write(
+        &self,
+        backend: Box<dyn StorageBackend>,
+        path: &Path,
+        create: bool,
+    ) -> Result<Box<dyn StorageBackend>, DatabaseError> {
+        if !self.double_write {
+            return Ok(backend);
+        }
+        let region =
+            FileBackend::open(double_write_path(path))?.with_page_size(backend.page_size());
+        if create {
+            region.truncate(0)?;
+        }
+        Ok(Box::new(DoubleWriteBackend::new(backend, region)?))
+    }
+}
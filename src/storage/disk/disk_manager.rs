@@ -1,16 +1,618 @@
-use std::io::{Read, Seek, Write};
+use std::collections::HashMap;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard};
 
-enum DataSource {
-    Memory,
-    File(String),
+use super::data_file::{DataFileOptions, OpenMode};
+use super::double_write::double_write_path;
+use super::storage_backend::StorageBackend;
+use crate::config::DATA_FILE_EXTENT_PAGES;
+use crate::errors::{DatabaseError, TablespaceError};
+use crate::storage::page::{HeaderPage, DATABASE_FORMAT_VERSION};
+use crate::storage::{FileId, PageAddress, PageId, PageNo};
+
+/// The name of the tablespace stored in the database file itself. It always has the file id 0.
+pub const DEFAULT_TABLESPACE: &str = "default";
+
+/// When writes are made durable with fsync, like SQLite's `synchronous` pragma
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never. A crash of the machine can lose or corrupt anything written since the files were
+    /// opened, but a crash of the process alone loses nothing.
+    Off,
+    /// At checkpoints, when the buffer pool flushes every dirty page
+    Normal,
+    /// After every page write, before the write is reported as done, and at checkpoints
+    #[default]
+    Full,
+}
+
+/// Maps every data file of the database, one per tablespace, to the storage backend holding its
+/// pages, and grows the files when pages past their end are accessed. The disk scheduler is the
+/// only one talking to it.
+pub struct DiskManager {
+    tablespaces: RwLock<Tablespaces>,
+    /// The page size of the database file, which every data file shares
+    page_size: usize,
+    /// Data files grow by this many pages at a time
+    extent_pages: u64,
+    durability: Durability,
+    /// Pages are never written and files never grow
+    read_only: bool,
+    /// How the data files of new tablespaces are opened
+    file_options: DataFileOptions,
 }
 
-pub struct DiskManager<R: Read + Write + Seek> {
-    reader: R,
+struct Tablespaces {
+    files: HashMap<FileId, Arc<DataFile>>,
+    names: HashMap<String, FileId>,
 }
 
-impl<R: Read + Write + Seek> DiskManager<R> {
-    pub fn new(reader: R) -> Self {
-        DiskManager { reader }
+/// A data file and the number of pages allocated in it
+struct DataFile {
+    backend: Box<dyn StorageBackend>,
+    /// Only grows, unless the file is truncated. Reading it is enough to know a page is
+    /// allocated, growing or truncating the file takes the lock so concurrent workers don't
+    /// allocate the same extent twice.
+    n_allocated_pages: AtomicU64,
+    growth: Mutex<()>,
+}
+
+impl DiskManager {
+    /// Creates a disk manager whose default tablespace is stored in the given backend
+    pub fn new(backend: impl StorageBackend + 'static) -> Self {
+        DiskManager::with_extent_pages(backend, DATA_FILE_EXTENT_PAGES)
+    }
+
+    /// Like `new`, but data files grow by `extent_pages` pages at a time
+    pub fn with_extent_pages(backend: impl StorageBackend + 'static, extent_pages: u64) -> Self {
+        assert!(
+            extent_pages > 0,
+            "Data files must grow by at least one page"
+        );
+        let page_size = backend.page_size();
+        let tablespaces = Tablespaces {
+            files: HashMap::from([(0, Arc::new(DataFile::new(Box::new(backend))))]),
+            names: HashMap::from([(DEFAULT_TABLESPACE.to_string(), 0)]),
+        };
+        DiskManager {
+            tablespaces: RwLock::new(tablespaces),
+            page_size,
+            extent_pages,
+            durability: Durability::default(),
+            read_only: false,
+            file_options: DataFileOptions::default(),
+        }
+    }
+
+    /// Opens the database file at `path`, holding pages of `page_size` bytes, as the default
+    /// tablespace. The data files of tablespaces are opened later with the same options. Opening
+    /// it read-only makes the disk manager read-only.
+    pub fn open(
+        path: &Path,
+        page_size: usize,
+        file_options: DataFileOptions,
+        mode: OpenMode,
+    ) -> Result<Self, DatabaseError> {
+        let backend = file_options.open(path, 0, page_size, mode)?;
+        let mut disk_manager = DiskManager::new(backend);
+        disk_manager.set_file_options(file_options);
+        disk_manager.set_read_only(mode == OpenMode::ReadOnly);
+        Ok(disk_manager)
+    }
+
+    /// Data files grow by `extent_pages` pages at a time
+    pub fn set_extent_pages(&mut self, extent_pages: u64) {
+        assert!(
+            extent_pages > 0,
+            "Data files must grow by at least one page"
+        );
+        self.extent_pages = extent_pages;
+    }
+
+    /// Changes how the data files of new tablespaces are opened
+    pub fn set_file_options(&mut self, file_options: DataFileOptions) {
+        self.file_options = file_options;
+    }
+
+    pub fn file_options(&self) -> &DataFileOptions {
+        &self.file_options
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Makes every write fail. Reading past the end of a file gives an empty page without
+    /// growing the file.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Makes the pages of the backend addressable with the given file id. The id is part of the
+    /// page ids stored in the pages, so a data file must be attached with the same id every time.
+    /// The backend must have the page size of the database file.
+    pub fn attach_tablespace(
+        &self,
+        name: &str,
+        file_id: FileId,
+        backend: impl StorageBackend + 'static,
+    ) -> Result<(), TablespaceError> {
+        let mut tablespaces = self
+            .tablespaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.insert_tablespace(&mut tablespaces, name, file_id, Box::new(backend))
+    }
+
+    /// Creates a tablespace stored in a new data file at `path` and returns the file id its pages
+    /// are addressed with. The first page of the file is its header, which records the file id.
+    /// The id is picked and the file attached under the same lock, so concurrent creations get
+    /// different ids, and the file is deleted if it can't be set up.
+    pub fn create_tablespace(&self, name: &str, path: &Path) -> Result<FileId, DatabaseError> {
+        if self.read_only {
+            return Err(DatabaseError::ReadOnly);
+        }
+        let mut tablespaces = self
+            .tablespaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if tablespaces.names.contains_key(name) {
+            return Err(TablespaceError::NameInUse(name.to_string()).into());
+        }
+        let file_id = tablespaces.next_file_id();
+        let backend = self
+            .file_options
+            .open(path, file_id, self.page_size, OpenMode::Create)?;
+        let result = self
+            .write_header(backend.as_ref(), file_id)
+            .map_err(DatabaseError::from)
+            .and_then(|()| Ok(self.insert_tablespace(&mut tablespaces, name, file_id, backend)?));
+        if let Err(err) = result {
+            remove_data_file(path);
+            return Err(err);
+        }
+        Ok(file_id)
+    }
+
+    /// Opens the data file at `path` of a tablespace created with `create_tablespace`. Fails if
+    /// its header doesn't record the given file id, the one it was created with. It is opened
+    /// read-only if the disk manager is.
+    pub fn open_tablespace(
+        &self,
+        name: &str,
+        file_id: FileId,
+        path: &Path,
+    ) -> Result<(), DatabaseError> {
+        // Opening a data file creates it if it doesn't exist
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("The data file {path:?} of tablespace {name} doesn't exist"),
+            )
+            .into());
+        }
+        let mode = match self.read_only {
+            true => OpenMode::ReadOnly,
+            false => OpenMode::Open,
+        };
+        let backend = self
+            .file_options
+            .open(path, file_id, self.page_size, mode)?;
+        self.check_header(backend.as_ref(), file_id, path)?;
+        self.attach_tablespace(name, file_id, backend)?;
+        Ok(())
+    }
+
+    /// Detaches the tablespace stored in the data file with the given id and deletes the file.
+    /// None of its pages must be in the buffer pool.
+    pub(crate) fn remove_tablespace(&self, file_id: FileId) -> Result<(), DatabaseError> {
+        if self.read_only {
+            return Err(DatabaseError::ReadOnly);
+        }
+        let mut tablespaces = self
+            .tablespaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(file) = tablespaces.files.remove(&file_id) else {
+            return Ok(());
+        };
+        tablespaces.names.retain(|_, id| *id != file_id);
+        log::debug!("Removing data file {file_id} at {:?}", file.backend.path());
+        if let Some(path) = file.backend.path() {
+            remove_data_file(path);
+        }
+        Ok(())
+    }
+
+    /// The file id of the tablespace with the given name
+    pub fn tablespace(&self, name: &str) -> Option<FileId> {
+        self.lock().names.get(name).copied()
+    }
+
+    /// The ids of every attached data file, in increasing order
+    pub fn file_ids(&self) -> Vec<FileId> {
+        let mut file_ids: Vec<_> = self.lock().files.keys().copied().collect();
+        file_ids.sort_unstable();
+        file_ids
+    }
+
+    /// The path of the database file. None if the data doesn't live in a file.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.file_path(0)
+    }
+
+    /// The path of the data file with the given id. None if the data doesn't live in a file.
+    pub fn file_path(&self, file_id: FileId) -> Option<PathBuf> {
+        self.file(file_id)
+            .ok()?
+            .backend
+            .path()
+            .map(Path::to_path_buf)
+    }
+
+    /// The number of pages allocated in the data file with the given id, written or not. None if
+    /// there is no such file.
+    pub fn allocated_pages(&self, file_id: FileId) -> Option<u64> {
+        let file = self.file(file_id).ok()?;
+        Some(file.n_allocated_pages.load(Ordering::Acquire))
+    }
+
+    /// Reads the page into the buffer. Reading past the end of the file is not an error, we
+    /// interpret it as the buffer pool wanting to read an empty page, so the file grows to hold it.
+    pub fn read_page(&self, page_id: PageId, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.read_pages(page_id, &mut [buffer])
+    }
+
+    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> std::io::Result<()> {
+        self.write_pages(page_id, &[data])
+    }
+
+    /// Reads adjacent pages of the same file, starting at `first_page_id`, with a single IO
+    /// operation. Pages past the end of the file are read as empty pages like in `read_page`.
+    pub fn read_pages(
+        &self,
+        first_page_id: PageId,
+        buffers: &mut [&mut [u8]],
+    ) -> std::io::Result<()> {
+        let address = PageAddress::of(first_page_id);
+        let file = self.file(address.file_id)?;
+        if self.read_only {
+            let n_allocated_pages = file.n_allocated_pages.load(Ordering::Acquire);
+            let n_existing_pages = n_allocated_pages
+                .saturating_sub(address.page_no as u64)
+                .min(buffers.len() as u64) as usize;
+            let (existing, past_end) = buffers.split_at_mut(n_existing_pages);
+            if !existing.is_empty() {
+                file.backend.read_pages(address.page_no, existing)?;
+            }
+            past_end.iter_mut().for_each(|buffer| buffer.fill(0));
+            return Ok(());
+        }
+        self.allocate(&file, address.page_no, buffers.len())?;
+        file.backend.read_pages(address.page_no, buffers)
+    }
+
+    /// Writes adjacent pages of the same file, starting at `first_page_id`, with a single IO
+    /// operation
+    pub fn write_pages(&self, first_page_id: PageId, data: &[&[u8]]) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        let address = PageAddress::of(first_page_id);
+        let file = self.file(address.file_id)?;
+        self.allocate(&file, address.page_no, data.len())?;
+        file.backend.write_pages(address.page_no, data)?;
+        if self.durability == Durability::Full {
+            file.backend.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Makes every write to every data file durable, unless durability is off. Called at
+    /// checkpoints.
+    pub fn sync(&self) -> std::io::Result<()> {
+        if self.durability == Durability::Off || self.read_only {
+            return Ok(());
+        }
+        let files: Vec<_> = self.lock().files.values().cloned().collect();
+        for file in files {
+            file.backend.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks the data file to its first `n_pages` pages, giving the rest back to the file
+    /// system. The pages past the new end must not be in use, reading them again gives empty
+    /// pages. Files never grow this way.
+    pub fn truncate(&self, file_id: FileId, n_pages: u64) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        let file = self.file(file_id)?;
+        let _growth = file.growth.lock().unwrap_or_else(PoisonError::into_inner);
+        if n_pages >= file.n_allocated_pages.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        log::debug!(
+            "Truncating data file {:?} to {n_pages} pages",
+            file.backend.path()
+        );
+        file.backend.truncate(n_pages)?;
+        if self.durability != Durability::Off {
+            file.backend.sync()?;
+        }
+        file.n_allocated_pages.store(n_pages, Ordering::Release);
+        Ok(())
+    }
+
+    /// Makes a page written without going through `write_pages` durable if durability is full
+    #[cfg_attr(not(feature = "io_uring"), allow(unused))]
+    pub(crate) fn complete_page_write(&self, page_id: PageId) -> std::io::Result<()> {
+        let file = self.file(PageAddress::of(page_id).file_id)?;
+        if self.durability != Durability::Full {
+            return Ok(());
+        }
+        file.backend.sync()
+    }
+
+    /// Grows the file of the page so it can be written without growing the file again
+    #[cfg_attr(not(feature = "io_uring"), allow(unused))]
+    pub(crate) fn allocate_page(&self, page_id: PageId) -> std::io::Result<()> {
+        let address = PageAddress::of(page_id);
+        let file = self.file(address.file_id)?;
+        self.allocate(&file, address.page_no, 1)
+    }
+
+    #[cfg_attr(not(feature = "io_uring"), allow(unused))]
+    pub(crate) fn raw_fd(&self, file_id: FileId) -> Option<RawFd> {
+        self.file(file_id).ok()?.backend.raw_fd()
+    }
+
+    /// Makes sure the `n_pages` pages starting at `first_page_no` are allocated, growing the file
+    /// by whole extents if they are not
+    fn allocate(
+        &self,
+        file: &DataFile,
+        first_page_no: PageNo,
+        n_pages: usize,
+    ) -> std::io::Result<()> {
+        let end = first_page_no as u64 + n_pages as u64;
+        if end <= file.n_allocated_pages.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        let _growth = file.growth.lock().unwrap_or_else(PoisonError::into_inner);
+        if end <= file.n_allocated_pages.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let mut n_allocated_pages = end.next_multiple_of(self.extent_pages);
+        // The last extent of a storage with a size limit is cut short to fit in it
+        if let Some(max_pages) = file.backend.max_pages() {
+            if end <= max_pages {
+                n_allocated_pages = n_allocated_pages.min(max_pages);
+            }
+        }
+        log::debug!(
+            "Growing data file {:?} to {n_allocated_pages} pages",
+            file.backend.path()
+        );
+        file.backend.allocate(n_allocated_pages)?;
+        file.n_allocated_pages
+            .store(n_allocated_pages, Ordering::Release);
+        Ok(())
+    }
+
+    fn insert_tablespace(
+        &self,
+        tablespaces: &mut Tablespaces,
+        name: &str,
+        file_id: FileId,
+        backend: Box<dyn StorageBackend>,
+    ) -> Result<(), TablespaceError> {
+        if backend.page_size() != self.page_size {
+            return Err(TablespaceError::PageSizeMismatch {
+                expected: self.page_size,
+                found: backend.page_size(),
+            });
+        }
+        if tablespaces.names.contains_key(name) {
+            return Err(TablespaceError::NameInUse(name.to_string()));
+        }
+        if tablespaces.files.contains_key(&file_id) {
+            return Err(TablespaceError::FileIdInUse(file_id));
+        }
+        log::debug!(
+            "Attaching tablespace {name} as file {file_id} at {:?}",
+            backend.path()
+        );
+        tablespaces
+            .files
+            .insert(file_id, Arc::new(DataFile::new(backend)));
+        tablespaces.names.insert(name.to_string(), file_id);
+        Ok(())
+    }
+
+    /// Writes the header of a new data file and makes it durable
+    fn write_header(&self, backend: &dyn StorageBackend, file_id: FileId) -> std::io::Result<()> {
+        let mut page = vec![0u8; self.page_size];
+        HeaderPage::init(page.as_mut_slice(), file_id);
+        backend.write_page(0, &page)?;
+        if self.durability != Durability::Off {
+            backend.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the data file at `path` was created as the data file with the given id, by a
+    /// database with the same page size and format
+    fn check_header(
+        &self,
+        backend: &dyn StorageBackend,
+        file_id: FileId,
+        path: &Path,
+    ) -> Result<(), DatabaseError> {
+        let not_a_data_file = || DatabaseError::NotADatabase(path.to_path_buf());
+        if backend.len()? == 0 {
+            return Err(not_a_data_file());
+        }
+        let mut page = vec![0u8; self.page_size];
+        backend.read_page(0, &mut page)?;
+        let header = HeaderPage::new(page.as_slice()).map_err(|_| not_a_data_file())?;
+        if !header.has_valid_magic() || header.page_size() != self.page_size {
+            return Err(not_a_data_file());
+        }
+        if header.format_version() != DATABASE_FORMAT_VERSION {
+            return Err(DatabaseError::UnsupportedFormatVersion(
+                header.format_version(),
+            ));
+        }
+        if header.file_id() != file_id {
+            return Err(TablespaceError::FileIdMismatch {
+                expected: file_id,
+                found: header.file_id(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn file(&self, file_id: FileId) -> std::io::Result<Arc<DataFile>> {
+        self.lock().files.get(&file_id).cloned().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No data file with id {file_id}"),
+            )
+        })
+    }
+
+    fn lock(&self) -> RwLockReadGuard<'_, Tablespaces> {
+        self.tablespaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Deletes a data file and its double-write buffer, if it has one
+fn remove_data_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        log::warn!("Could not remove the data file {path:?}: {err}");
+    }
+    let _ = std::fs::remove_file(double_write_path(path));
+}
+
+impl Tablespaces {
+    /// The smallest file id greater than every attached one
+    fn next_file_id(&self) -> FileId {
+        self.files.keys().max().map_or(0, |file_id| file_id + 1)
+    }
+}
+
+fn read_only_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "The data files are open read-only",
+    )
+}
+
+impl DataFile {
+    fn new(backend: Box<dyn StorageBackend>) -> Self {
+        // The file grows on the first access if its size is unknown
+        let n_allocated_pages = backend.len().unwrap_or(0);
+        DataFile {
+            backend,
+            n_allocated_pages: AtomicU64::new(n_allocated_pages),
+            growth: Mutex::new(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::{FileBackend, MemoryBackend};
+
+    #[test]
+    fn test_files_grow_by_extents() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let disk_manager = DiskManager::with_extent_pages(disk.clone(), 4);
+        let mut buffer = vec![1u8; PAGE_SIZE];
+
+        // Reading past the end gives an empty page and allocates a whole extent
+        disk_manager.read_page(2, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 0));
+        assert_eq!(disk_manager.allocated_pages(0), Some(4));
+        assert_eq!(disk.len().unwrap(), 4);
+
+        // Pages inside the extent don't grow the file
+        disk_manager.write_page(3, &[2u8; PAGE_SIZE]).unwrap();
+        assert_eq!(disk.len().unwrap(), 4);
+
+        disk_manager
+            .write_pages(3, &[&[3u8; PAGE_SIZE][..]; 3])
+            .unwrap();
+        assert_eq!(disk_manager.allocated_pages(0), Some(8));
+        assert_eq!(disk.len().unwrap(), 8);
+        assert_eq!(disk_manager.allocated_pages(1), None);
+    }
+
+    #[test]
+    fn test_truncate() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let disk_manager = DiskManager::with_extent_pages(disk.clone(), 4);
+        disk_manager.write_page(5, &[5u8; PAGE_SIZE]).unwrap();
+        assert_eq!(disk.len().unwrap(), 8);
+
+        disk_manager.truncate(0, 10).unwrap();
+        assert_eq!(disk.len().unwrap(), 8);
+        disk_manager.truncate(0, 3).unwrap();
+        assert_eq!(disk.len().unwrap(), 3);
+        assert_eq!(disk_manager.allocated_pages(0), Some(3));
+
+        // The truncated pages are empty when the file grows again
+        let mut buffer = vec![1u8; PAGE_SIZE];
+        disk_manager.read_page(5, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 0));
+        assert_eq!(disk_manager.allocated_pages(0), Some(8));
+    }
+
+    #[test]
+    fn test_file_preallocation() {
+        setup_logger();
+        let path = std::env::temp_dir().join(format!("maridbel-{}-extents.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let disk_manager = DiskManager::with_extent_pages(FileBackend::create(&path).unwrap(), 16);
+
+        disk_manager.write_page(0, &[1u8; PAGE_SIZE]).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            16 * PAGE_SIZE as u64
+        );
+        // The data file is attached again with the pages it already has
+        drop(disk_manager);
+        let disk_manager = DiskManager::with_extent_pages(FileBackend::open(&path).unwrap(), 16);
+        assert_eq!(disk_manager.allocated_pages(0), Some(16));
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,16 +1,19 @@
-use crate::config::PAGE_SIZE;
+use crate::config::{
+    DISK_SCHEDULER_N_WORKERS, IO_MAX_COALESCED_PAGES, IO_MAX_RETRIES, IO_PRIORITY_AGING,
+    IO_RETRY_BACKOFF,
+};
 use crate::errors::ScheduleError;
-use crate::storage::page::THE_EMPTY_PAGE;
-use crate::storage::{Frame, PageId};
+use crate::storage::disk::disk_manager::DiskManager;
+use crate::storage::{Frame, PageAddress, PageId};
 use oneshot::{OneshotChannelReceiver, OneshotChannelSender};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub type ScheduleResult = Result<(), ScheduleError>;
 
-enum QueueRequest {
+pub(super) enum QueueRequest {
     Read {
         page_id: PageId,
         buffer: Arc<RwLock<Frame>>,
@@ -23,160 +26,671 @@ enum QueueRequest {
     },
 }
 
+impl QueueRequest {
+    pub(super) fn page_id(&self) -> PageId {
+        match self {
+            QueueRequest::Read { page_id, .. } | QueueRequest::Write { page_id, .. } => *page_id,
+        }
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self, QueueRequest::Write { .. })
+    }
+
+    fn frame(&self) -> &Arc<RwLock<Frame>> {
+        match self {
+            QueueRequest::Read { buffer, .. } => buffer,
+            QueueRequest::Write { data, .. } => data,
+        }
+    }
+
+    fn into_channel(self) -> OneshotChannelSender<ScheduleResult> {
+        match self {
+            QueueRequest::Read { channel, .. } | QueueRequest::Write { channel, .. } => channel,
+        }
+    }
+}
+
+/// Requests of a higher class are served first. A request gets promoted one class every
+/// IO_PRIORITY_AGING it waits in the queue, so lower classes are never starved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoPriority {
+    /// Someone is waiting for the page, such as a page miss in the buffer pool
+    Foreground = 0,
+    /// The page will probably be needed soon
+    Prefetch = 1,
+    /// Nobody is waiting for the page, such as flushes and checkpoints
+    Background = 2,
+}
+
+const N_IO_PRIORITIES: usize = IoPriority::Background as usize + 1;
+
+struct QueuedRequest {
+    request: QueueRequest,
+    priority: IoPriority,
+    enqueued_at: Instant,
+    /// Orders the requests by the time they were scheduled
+    sequence: u64,
+}
+
+impl QueuedRequest {
+    /// The class the request is served as after aging, lower is served first
+    fn rank(&self, now: Instant) -> u128 {
+        let promotions =
+            now.duration_since(self.enqueued_at).as_nanos() / IO_PRIORITY_AGING.as_nanos();
+        (self.priority as u128).saturating_sub(promotions)
+    }
+}
+
+/// How the scheduler deals with IO errors that may go away by themselves, such as timeouts.
+/// Any other error fails the request right away.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a request is retried before giving up
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Each retry waits one backoff more than the
+    /// previous one.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: IO_MAX_RETRIES,
+            backoff: IO_RETRY_BACKOFF,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RequestsQueue {
+    /// The requests of each page in the order they were scheduled, sorted by page so the
+    /// requests of adjacent pages are found without scanning the queue.
+    requests: BTreeMap<PageId, VecDeque<QueuedRequest>>,
+    /// For each priority class, the pages with a request of that class and not in flight, in the
+    /// order their oldest request of the class was scheduled. Older requests are promoted first,
+    /// so the first page of each class has the best rank of the class.
+    ready: [BTreeSet<(u64, PageId)>; N_IO_PRIORITIES],
+    /// Pages with a request being served. A page has at most one request in flight, so requests
+    /// for the same page complete in the order they were scheduled.
+    in_flight: HashSet<PageId>,
+    /// Once set, no new requests are accepted and the workers exit after draining the queue
+    is_shutdown: bool,
+    next_sequence: u64,
+}
+
+impl RequestsQueue {
+    fn push(&mut self, request: QueueRequest, priority: IoPriority) {
+        let page_id = request.page_id();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let requests = self.requests.entry(page_id).or_default();
+        let is_first_of_class = requests.iter().all(|queued| queued.priority != priority);
+        requests.push_back(QueuedRequest {
+            request,
+            priority,
+            enqueued_at: Instant::now(),
+            sequence,
+        });
+        if is_first_of_class && !self.in_flight.contains(&page_id) {
+            self.ready[priority as usize].insert((sequence, page_id));
+        }
+    }
+
+    /// The page whose oldest request must be served next. Only the oldest request of each page
+    /// can be served, and it takes the best rank of all the requests for its page, since they
+    /// have to wait for it anyway. Ties go to the request giving the rank that was scheduled
+    /// first.
+    fn next_page(&self, now: Instant) -> Option<PageId> {
+        self.ready
+            .iter()
+            .filter_map(|pages| {
+                let (sequence, page_id) = pages.first()?;
+                let rank = self.requests[page_id]
+                    .iter()
+                    .find(|queued| queued.sequence == *sequence)?
+                    .rank(now);
+                Some(((rank, *sequence), *page_id))
+            })
+            .min()
+            .map(|(_, page_id)| page_id)
+    }
+
+    /// Takes the oldest request of the page and marks the page as in flight
+    fn take(&mut self, page_id: PageId) -> Option<QueueRequest> {
+        let requests = self.requests.get_mut(&page_id)?;
+        for queued in requests.iter() {
+            self.ready[queued.priority as usize].remove(&(queued.sequence, page_id));
+        }
+        let request = requests.pop_front()?.request;
+        if requests.is_empty() {
+            self.requests.remove(&page_id);
+        }
+        self.in_flight.insert(page_id);
+        Some(request)
+    }
+
+    /// The request of the page in flight completed, its next request can be served
+    fn complete(&mut self, page_id: PageId) {
+        self.in_flight.remove(&page_id);
+        let Some(requests) = self.requests.get(&page_id) else {
+            return;
+        };
+        let mut seen = [false; N_IO_PRIORITIES];
+        for queued in requests {
+            let class = queued.priority as usize;
+            if !seen[class] {
+                seen[class] = true;
+                self.ready[class].insert((queued.sequence, page_id));
+            }
+        }
+    }
+}
+
+/// The queue shared between the scheduler and its workers. Workers sleep on the condvar until
+/// there is a request they can serve or the scheduler shuts down.
+pub(super) struct SharedQueue {
+    queue: Mutex<RequestsQueue>,
+    condvar: Condvar,
+}
+
+/// Marks a page as no longer in flight when dropped, even if the worker panicked serving it
+pub(super) struct InFlightRequest<'a> {
+    shared: &'a SharedQueue,
+    page_id: PageId,
+}
+
 pub struct DiskScheduler {
-    requests_queue: Arc<Mutex<Vec<QueueRequest>>>,
-    handle: JoinHandle<()>,
-    // disk_manager: DiskManager<R>,
+    shared: Arc<SharedQueue>,
+    disk_manager: Arc<DiskManager>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    /// Without workers, requests are served right away by the thread scheduling them
+    serve_inline: bool,
+    retry_policy: RetryPolicy,
 }
 
 impl DiskScheduler {
-    pub fn new<R>(mut reader: R) -> Self
-    where
-        R: Read + Write + Seek + Send + 'static,
-    {
-        let queue = Arc::new(Mutex::new(Vec::new()));
-        let moved_queue = queue.clone();
-
-        let handle = std::thread::spawn(move || {
-            let queue = moved_queue;
-
-            // TODO: where io_uring will fit here
-
-            loop {
-                let maybe_request = {
-                    let mut queue = queue.lock().unwrap();
-                    queue.pop()
-                };
-
-                match maybe_request {
-                    Some(QueueRequest::Read {
-                        page_id,
-                        buffer,
-                        channel,
-                    }) => {
-                        log::trace!("DiskScheduler->read(page_id={page_id})");
-                        let mut buffer = buffer.write().expect("could not lock buffer for reading");
-
-                        if let Err(e) =
-                            reader.seek(SeekFrom::Start(page_id_to_file_offset(page_id)))
-                        {
-                            channel.send(Err(ScheduleError::IOError(e))).unwrap();
-                            return;
-                        }
-
-                        match reader.read_exact(&mut buffer.data) {
-                            Ok(_) => {
-                                // Unwrapped because the caller must not drop the receiver
-                                channel.send(Ok(())).unwrap();
-                            }
-                            // EOF are not errors.W e interpret this as the buffer pool wanting
-                            // to read an empty page
-                            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                                reader.write_all(&THE_EMPTY_PAGE).unwrap();
-                                buffer.data.copy_from_slice(&THE_EMPTY_PAGE);
-                                channel.send(Ok(())).unwrap();
-                            }
-                            Err(e) => {
-                                channel.send(Err(ScheduleError::IOError(e))).unwrap();
-                            }
-                        }
-                    }
-                    Some(QueueRequest::Write {
-                        page_id,
-                        data,
-                        channel,
-                    }) => {
-                        log::trace!("DiskScheduler->write(page_id={page_id})");
-                        let frame = data.write().expect("could not lock buffer for writing");
-
-                        if let Err(e) =
-                            reader.seek(SeekFrom::Start(page_id_to_file_offset(page_id)))
-                        {
-                            channel.send(Err(ScheduleError::IOError(e))).unwrap();
-                            return;
-                        }
-
-                        match reader.write_all(&frame.data) {
-                            Ok(_) => {
-                                channel.send(Ok(())).unwrap();
-                            }
-                            Err(e) => {
-                                channel.send(Err(ScheduleError::IOError(e))).unwrap();
-                            }
-                        }
-                    }
-                    None => {
-                        // No requests in the queue, sleep for a while
-                        std::thread::sleep(Duration::from_millis(1));
+    pub fn new(disk_manager: DiskManager) -> Self {
+        DiskScheduler::with_config(
+            disk_manager,
+            DISK_SCHEDULER_N_WORKERS,
+            RetryPolicy::default(),
+        )
+    }
+
+    /// Starts `n_workers` threads serving IO requests in parallel. With no workers, requests are
+    /// served by the thread scheduling them, which is cheaper when IO doesn't block, such as
+    /// with `MemoryBackend`.
+    pub fn with_config(
+        disk_manager: DiskManager,
+        n_workers: usize,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        let shared = Arc::new(SharedQueue::new());
+        let disk_manager = Arc::new(disk_manager);
+
+        let handles = (0..n_workers)
+            .map(|worker_id| {
+                let shared = shared.clone();
+                let disk_manager = disk_manager.clone();
+                std::thread::spawn(move || {
+                    #[cfg(feature = "io_uring")]
+                    if super::io_uring_worker::run_worker(&shared, &disk_manager, retry_policy)
+                        .is_ok()
+                    {
+                        return;
                     }
-                }
-            }
-        });
+                    run_worker(worker_id, &shared, &disk_manager, retry_policy)
+                })
+            })
+            .collect();
 
         DiskScheduler {
-            // disk_manager, // TODO: move this manager here, or go without it
-            requests_queue: queue.clone(),
-            handle,
+            shared,
+            disk_manager,
+            handles: Mutex::new(handles),
+            serve_inline: n_workers == 0,
+            retry_policy,
         }
     }
 
+    /// The disk manager the workers do IO with, to attach tablespaces while the database runs
+    pub fn disk_manager(&self) -> &DiskManager {
+        &self.disk_manager
+    }
+
     pub fn schedule_read(
         &self,
         page_id: PageId,
         buffer: Arc<RwLock<Frame>>,
-    ) -> OneshotChannelReceiver<ScheduleResult> {
+        priority: IoPriority,
+    ) -> Result<OneshotChannelReceiver<ScheduleResult>, ScheduleError> {
         let (tx, rx) = oneshot::channel::<ScheduleResult>();
 
-        if self.handle.is_finished() {
-            panic!("Disk scheduler thread has finished. TODO: panic gracefully");
-        }
-        self.requests_queue
-            .lock()
-            .unwrap()
-            .push(QueueRequest::Read {
+        self.submit(
+            QueueRequest::Read {
                 page_id,
                 buffer,
                 channel: tx,
-            });
+            },
+            priority,
+        )?;
 
-        rx
+        Ok(rx)
     }
 
     pub fn schedule_write(
         &self,
         page_id: PageId,
         data: Arc<RwLock<Frame>>,
-    ) -> OneshotChannelReceiver<ScheduleResult> {
+        priority: IoPriority,
+    ) -> Result<OneshotChannelReceiver<ScheduleResult>, ScheduleError> {
         let (tx, rx) = oneshot::channel::<ScheduleResult>();
 
-        if self.handle.is_finished() {
-            panic!("Disk scheduler thread has finished. TODO: panic gracefully");
-        }
-        self.requests_queue
-            .lock()
-            .unwrap()
-            .push(QueueRequest::Write {
+        self.submit(
+            QueueRequest::Write {
                 page_id,
                 data,
                 channel: tx,
+            },
+            priority,
+        )?;
+
+        Ok(rx)
+    }
+
+    /// Reads the page into the buffer and waits for the read to complete
+    pub fn read_page(
+        &self,
+        page_id: PageId,
+        buffer: Arc<RwLock<Frame>>,
+        priority: IoPriority,
+    ) -> ScheduleResult {
+        wait_for(self.schedule_read(page_id, buffer, priority)?)
+    }
+
+    /// Writes the frame into the page and waits for the write to complete
+    pub fn write_page(
+        &self,
+        page_id: PageId,
+        data: Arc<RwLock<Frame>>,
+        priority: IoPriority,
+    ) -> ScheduleResult {
+        wait_for(self.schedule_write(page_id, data, priority)?)
+    }
+
+    fn submit(&self, request: QueueRequest, priority: IoPriority) -> Result<(), ScheduleError> {
+        if !self.serve_inline {
+            return self.shared.push(request, priority);
+        }
+        if self.shared.is_shutdown() {
+            return Err(ScheduleError::SchedulerShutdown);
+        }
+        serve_request(0, &self.disk_manager, self.retry_policy, request);
+        Ok(())
+    }
+
+    /// Stops accepting requests and waits for the workers to serve the pending ones.
+    /// Calling it more than once is a no-op.
+    pub fn shutdown(&self) {
+        self.shared.close();
+
+        let handles =
+            std::mem::take(&mut *self.handles.lock().unwrap_or_else(PoisonError::into_inner));
+        for handle in handles {
+            if handle.join().is_err() {
+                log::error!("DiskScheduler worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for DiskScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_worker(
+    worker_id: usize,
+    shared: &SharedQueue,
+    disk_manager: &DiskManager,
+    retry_policy: RetryPolicy,
+) {
+    // If a worker dies, even by panicking, later requests are rejected instead of being queued
+    // forever
+    let _exit_guard = WorkerExitGuard(shared);
+
+    while let Some(batch) = shared.next_batch(IO_MAX_COALESCED_PAGES) {
+        if batch.len() > 1 {
+            match serve_coalesced(worker_id, disk_manager, &batch) {
+                Ok(()) => {
+                    for (request, _in_flight) in batch {
+                        complete_request(request.page_id(), request.into_channel(), Ok(()));
+                    }
+                    continue;
+                }
+                // Each request gets its own retries and its own error
+                Err(err) => log::debug!("Coalesced IO failed, serving requests one by one: {err}"),
+            }
+        }
+        for (request, _in_flight) in batch {
+            serve_request(worker_id, disk_manager, retry_policy, request);
+        }
+    }
+    log::debug!("DiskScheduler worker {worker_id} finished");
+}
+
+pub(super) fn serve_request(
+    worker_id: usize,
+    disk_manager: &DiskManager,
+    retry_policy: RetryPolicy,
+    request: QueueRequest,
+) {
+    match request {
+        QueueRequest::Read {
+            page_id,
+            buffer,
+            channel,
+        } => {
+            log::trace!("DiskScheduler[{worker_id}]->read(page_id={page_id})");
+            let mut buffer = buffer.write().unwrap_or_else(PoisonError::into_inner);
+
+            let result = retry_policy.run(page_id, || {
+                disk_manager.read_page(page_id, &mut buffer.data)
             });
+            drop(buffer);
+            complete_request(page_id, channel, result);
+        }
+        QueueRequest::Write {
+            page_id,
+            data,
+            channel,
+        } => {
+            log::trace!("DiskScheduler[{worker_id}]->write(page_id={page_id})");
+            let frame = data.write().unwrap_or_else(PoisonError::into_inner);
 
-        rx
+            let result =
+                retry_policy.run(page_id, || disk_manager.write_page(page_id, &frame.data));
+            drop(frame);
+            complete_request(page_id, channel, result);
+        }
     }
 }
 
-/* Utils */
+/// The page right after (`delta == 1`) or right before (`delta == -1`) the given one in its file.
+/// The last page of a file and the first page of the next one are not adjacent.
+fn adjacent_page(page_id: PageId, delta: i64) -> Option<PageId> {
+    let address = PageAddress::of(page_id);
+    let page_no = address.page_no.checked_add_signed(delta as i32)?;
+    Some(PageAddress::new(address.file_id, page_no).page_id())
+}
+
+/// Serves a batch of requests of the same kind for adjacent pages with a single IO operation
+fn serve_coalesced(
+    worker_id: usize,
+    disk_manager: &DiskManager,
+    batch: &[(QueueRequest, InFlightRequest<'_>)],
+) -> std::io::Result<()> {
+    let first_page_id = batch[0].0.page_id();
+    let is_write = batch[0].0.is_write();
+    log::trace!(
+        "DiskScheduler[{worker_id}]->{}(page_ids={first_page_id}..{})",
+        if is_write { "write" } else { "read" },
+        first_page_id + batch.len() as PageId
+    );
 
-fn page_id_to_file_offset(id: PageId) -> u64 {
-    id as u64 * PAGE_SIZE as u64
+    let mut frames: Vec<_> = batch
+        .iter()
+        .map(|(request, _)| {
+            request
+                .frame()
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+        })
+        .collect();
+    if is_write {
+        let data: Vec<&[u8]> = frames.iter().map(|frame| &frame.data[..]).collect();
+        disk_manager.write_pages(first_page_id, &data)
+    } else {
+        let mut buffers: Vec<&mut [u8]> =
+            frames.iter_mut().map(|frame| &mut frame.data[..]).collect();
+        disk_manager.read_pages(first_page_id, &mut buffers)
+    }
+}
+
+impl SharedQueue {
+    fn new() -> Self {
+        SharedQueue {
+            queue: Mutex::new(RequestsQueue::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn push(&self, request: QueueRequest, priority: IoPriority) -> Result<(), ScheduleError> {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        if queue.is_shutdown {
+            return Err(ScheduleError::SchedulerShutdown);
+        }
+        queue.push(request, priority);
+        self.condvar.notify_one();
+        Ok(())
+    }
+
+    /// Blocks until there is a request whose page is not in flight and marks its page as in
+    /// flight. Returns None once the scheduler was shut down and there is nothing left in the
+    /// queue.
+    pub(super) fn next_request(&self) -> Option<(QueueRequest, InFlightRequest<'_>)> {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(request) = self.take_request(&mut queue) {
+                return Some(request);
+            }
+            if queue.is_shutdown && queue.requests.is_empty() {
+                return None;
+            }
+            queue = self
+                .condvar
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Like `next_request` but also takes the requests of the same kind for the pages adjacent to
+    /// it in the same file, up to `max_batch_size` requests. The batch is sorted by page id and
+    /// has no gaps.
+    fn next_batch(
+        &self,
+        max_batch_size: usize,
+    ) -> Option<Vec<(QueueRequest, InFlightRequest<'_>)>> {
+        let first = self.next_request()?;
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let is_write = first.0.is_write();
+        let mut batch = VecDeque::from([first]);
+
+        while batch.len() < max_batch_size {
+            let next = batch
+                .back()
+                .and_then(|(request, _)| adjacent_page(request.page_id(), 1))
+                .and_then(|page_id| self.take_adjacent(&mut queue, page_id, is_write));
+            let Some(next) = next else { break };
+            batch.push_back(next);
+        }
+        while batch.len() < max_batch_size {
+            let previous = batch
+                .front()
+                .and_then(|(request, _)| adjacent_page(request.page_id(), -1))
+                .and_then(|page_id| self.take_adjacent(&mut queue, page_id, is_write));
+            let Some(previous) = previous else { break };
+            batch.push_front(previous);
+        }
+        Some(batch.into())
+    }
+
+    /// Takes the oldest request for the page if it is of the given kind and the page is not in
+    /// flight. Newer requests for the page must wait for it, so they are never taken.
+    fn take_adjacent(
+        &self,
+        queue: &mut RequestsQueue,
+        page_id: PageId,
+        is_write: bool,
+    ) -> Option<(QueueRequest, InFlightRequest<'_>)> {
+        if queue.in_flight.contains(&page_id) {
+            return None;
+        }
+        let oldest = queue.requests.get(&page_id)?.front()?;
+        if oldest.request.is_write() != is_write {
+            return None;
+        }
+        let request = queue.take(page_id)?;
+        Some((
+            request,
+            InFlightRequest {
+                shared: self,
+                page_id,
+            },
+        ))
+    }
+
+    /// Like `next_request` but returns None right away if there is no request to serve
+    #[cfg_attr(not(feature = "io_uring"), allow(unused))]
+    pub(super) fn try_next_request(&self) -> Option<(QueueRequest, InFlightRequest<'_>)> {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        self.take_request(&mut queue)
+    }
+
+    fn take_request(
+        &self,
+        queue: &mut RequestsQueue,
+    ) -> Option<(QueueRequest, InFlightRequest<'_>)> {
+        let page_id = queue.next_page(Instant::now())?;
+        let request = queue.take(page_id)?;
+        Some((
+            request,
+            InFlightRequest {
+                shared: self,
+                page_id,
+            },
+        ))
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_shutdown
+    }
+
+    fn close(&self) {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_shutdown = true;
+        self.condvar.notify_all();
+    }
+}
+
+impl Drop for InFlightRequest<'_> {
+    fn drop(&mut self) {
+        self.shared
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .complete(self.page_id);
+        // Requests for this page may be waiting for it, and any worker may be the one to pick
+        // them up
+        self.shared.condvar.notify_all();
+    }
+}
+
+impl RetryPolicy {
+    /// Runs the IO operation, retrying it while it fails with a transient error
+    fn run(
+        &self,
+        page_id: PageId,
+        mut operation: impl FnMut() -> std::io::Result<()>,
+    ) -> ScheduleResult {
+        let mut n_retries = 0;
+        loop {
+            match operation() {
+                Ok(()) => return Ok(()),
+                Err(err) => match self.on_error(page_id, err, n_retries) {
+                    Ok(()) => {
+                        n_retries += 1;
+                        std::thread::sleep(self.backoff * n_retries);
+                    }
+                    Err(err) => return Err(err),
+                },
+            }
+        }
+    }
+
+    /// Decides what to do after the `n_retries`-th retry failed. Ok means the operation should
+    /// be retried.
+    pub(super) fn on_error(
+        &self,
+        page_id: PageId,
+        err: std::io::Error,
+        n_retries: u32,
+    ) -> ScheduleResult {
+        if is_transient(&err) && n_retries < self.max_retries {
+            log::warn!(
+                "Transient IO error on page_id={page_id}, retry {}/{}: {err}",
+                n_retries + 1,
+                self.max_retries
+            );
+            Ok(())
+        } else {
+            log::error!("IO error on page_id={page_id}: {err}");
+            Err(err.into())
+        }
+    }
+}
+
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Sends the result to whoever scheduled the request. The caller may have stopped waiting for
+/// it, which is not a reason to bring the worker down.
+pub(super) fn complete_request(
+    page_id: PageId,
+    channel: OneshotChannelSender<ScheduleResult>,
+    result: ScheduleResult,
+) {
+    if channel.send(result).is_err() {
+        log::warn!("Request for page_id={page_id} completed but nobody was waiting for it");
+    }
+}
+
+/// Waits for a scheduled request to complete. A request dropped without an answer means the
+/// worker is gone.
+pub fn wait_for(receiver: OneshotChannelReceiver<ScheduleResult>) -> ScheduleResult {
+    receiver
+        .try_recv()
+        .unwrap_or(Err(ScheduleError::SchedulerShutdown))
+}
+
+pub(super) struct WorkerExitGuard<'a>(pub(super) &'a SharedQueue);
+
+impl Drop for WorkerExitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::PAGE_SIZE;
     use crate::shared::logger::setup_logger;
     use crate::storage::buffer::frame::Frame;
-    use std::io::Cursor;
+    use crate::storage::{FileBackend, MemoryBackend, PageNo, StorageBackend};
 
     #[test]
     fn test_disk_scheduler() {
@@ -193,16 +707,462 @@ mod tests {
         let data = b"A test string.";
         frame1.write().unwrap().data[0..data.len()].copy_from_slice(data);
 
-        let db = Cursor::new(vec![]);
-
-        let scheduler = DiskScheduler::new(db);
+        let scheduler = DiskScheduler::new(DiskManager::new(MemoryBackend::new()));
 
-        scheduler.schedule_write(0, frame1.clone()).recv().unwrap();
-        scheduler.schedule_read(0, frame2.clone()).recv().unwrap();
+        scheduler
+            .schedule_write(0, frame1.clone(), IoPriority::Foreground)
+            .unwrap()
+            .recv()
+            .unwrap();
+        scheduler
+            .schedule_read(0, frame2.clone(), IoPriority::Foreground)
+            .unwrap()
+            .recv()
+            .unwrap();
 
         let data1 = &frame1.read().unwrap().data;
         let data2 = &frame2.read().unwrap().data;
 
         assert_eq!(data1, data2, "Data mismatch");
     }
+
+    fn frame_filled_with(byte: u8) -> Arc<RwLock<Frame>> {
+        Arc::new(RwLock::new(Frame::new(
+            vec![byte; PAGE_SIZE].into_boxed_slice(),
+        )))
+    }
+
+    #[test]
+    #[cfg(feature = "io_uring")]
+    fn test_io_uring_submission_failure_falls_back_to_blocking_io() {
+        setup_logger();
+        let path = std::env::temp_dir().join(format!(
+            "maridbel-{}-io-uring-fallback.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let disk_manager = Arc::new(DiskManager::new(FileBackend::create(&path).unwrap()));
+        let shared = Arc::new(SharedQueue::new());
+        // The worker body of `with_config`, with a ring whose submissions fail
+        let worker = {
+            let shared = shared.clone();
+            let disk_manager = disk_manager.clone();
+            std::thread::spawn(move || {
+                let mut ring = io_uring::IoUring::new(8).unwrap();
+                let result = super::super::io_uring_worker::serve_with_ring(
+                    &shared,
+                    &disk_manager,
+                    RetryPolicy::default(),
+                    &mut ring,
+                    |_| Err(std::io::Error::other("injected submission failure")),
+                );
+                assert!(result.is_err());
+                run_worker(0, &shared, &disk_manager, RetryPolicy::default());
+            })
+        };
+        let scheduler = DiskScheduler {
+            shared,
+            disk_manager,
+            handles: Mutex::new(vec![worker]),
+            serve_inline: false,
+            retry_policy: RetryPolicy::default(),
+        };
+
+        // The request submitted to the ring fails, the next ones are served with blocking IO
+        assert!(scheduler
+            .write_page(0, frame_filled_with(1), IoPriority::Foreground)
+            .is_err());
+        scheduler
+            .write_page(1, frame_filled_with(2), IoPriority::Foreground)
+            .unwrap();
+        let target = frame_filled_with(0);
+        scheduler
+            .read_page(1, target.clone(), IoPriority::Foreground)
+            .unwrap();
+        assert!(target.read().unwrap().data.iter().all(|b| *b == 2));
+
+        drop(scheduler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_scheduler_serves_requests_in_order() {
+        setup_logger();
+        let scheduler = DiskScheduler::new(DiskManager::new(MemoryBackend::new()));
+
+        let first_write = scheduler
+            .schedule_write(0, frame_filled_with(1), IoPriority::Foreground)
+            .unwrap();
+        let second_write = scheduler
+            .schedule_write(0, frame_filled_with(2), IoPriority::Foreground)
+            .unwrap();
+        let target = frame_filled_with(0);
+        let read = scheduler
+            .schedule_read(0, target.clone(), IoPriority::Foreground)
+            .unwrap();
+
+        read.recv().unwrap();
+        first_write.recv().unwrap();
+        second_write.recv().unwrap();
+        assert!(target.read().unwrap().data.iter().all(|byte| *byte == 2));
+    }
+
+    #[test]
+    fn test_disk_scheduler_shutdown_drains_queue() {
+        setup_logger();
+        let scheduler = DiskScheduler::new(DiskManager::new(MemoryBackend::new()));
+
+        let receivers: Vec<_> = (0..16)
+            .map(|page_id| {
+                scheduler
+                    .schedule_write(
+                        page_id,
+                        frame_filled_with(page_id as u8),
+                        IoPriority::Foreground,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        scheduler.shutdown();
+
+        for receiver in receivers {
+            assert!(receiver.recv().is_ok());
+        }
+        assert!(matches!(
+            scheduler.schedule_read(0, frame_filled_with(0), IoPriority::Foreground),
+            Err(ScheduleError::SchedulerShutdown)
+        ));
+        // Shutting down twice is fine
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_disk_scheduler_without_workers() {
+        setup_logger();
+        let scheduler = DiskScheduler::with_config(
+            DiskManager::new(MemoryBackend::new()),
+            0,
+            RetryPolicy::default(),
+        );
+
+        // Requests are served before they are handed back
+        let write = scheduler
+            .schedule_write(3, frame_filled_with(7), IoPriority::Foreground)
+            .unwrap();
+        assert!(matches!(write.try_recv(), Ok(Ok(()))));
+        let target = frame_filled_with(0);
+        scheduler
+            .read_page(3, target.clone(), IoPriority::Foreground)
+            .unwrap();
+        assert!(target.read().unwrap().data.iter().all(|byte| *byte == 7));
+
+        scheduler.shutdown();
+        assert!(matches!(
+            scheduler.schedule_read(3, target, IoPriority::Foreground),
+            Err(ScheduleError::SchedulerShutdown)
+        ));
+    }
+
+    #[test]
+    fn test_disk_scheduler_keeps_page_order_across_workers() {
+        setup_logger();
+        let scheduler = DiskScheduler::with_config(
+            DiskManager::new(MemoryBackend::new()),
+            8,
+            RetryPolicy::default(),
+        );
+
+        // Every page gets a chain of writes followed by a read, all scheduled at once
+        let reads: Vec<_> = (0..32)
+            .map(|page_id| {
+                for byte in 1..=4 {
+                    scheduler
+                        .schedule_write(
+                            page_id,
+                            frame_filled_with(page_id as u8 + byte),
+                            IoPriority::Foreground,
+                        )
+                        .unwrap();
+                }
+                let target = frame_filled_with(0);
+                let read = scheduler
+                    .schedule_read(page_id, target.clone(), IoPriority::Foreground)
+                    .unwrap();
+                (page_id, target, read)
+            })
+            .collect();
+
+        for (page_id, target, read) in reads {
+            wait_for(read).unwrap();
+            let expected = page_id as u8 + 4;
+            assert!(target.read().unwrap().data.iter().all(|b| *b == expected));
+        }
+    }
+
+    fn empty_queue() -> SharedQueue {
+        SharedQueue {
+            queue: Mutex::new(RequestsQueue::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// A request nobody waits for
+    fn request(page_id: PageId, is_write: bool) -> QueueRequest {
+        let (channel, _) = oneshot::channel::<ScheduleResult>();
+        if is_write {
+            QueueRequest::Write {
+                page_id,
+                data: frame_filled_with(0),
+                channel,
+            }
+        } else {
+            QueueRequest::Read {
+                page_id,
+                buffer: frame_filled_with(0),
+                channel,
+            }
+        }
+    }
+
+    #[test]
+    fn test_next_batch_takes_adjacent_pages() {
+        setup_logger();
+        let shared = empty_queue();
+        let push = |page_id, is_write| {
+            shared
+                .push(request(page_id, is_write), IoPriority::Foreground)
+                .unwrap();
+        };
+        push(3, true);
+        push(5, true);
+        push(1, true);
+        push(4, false);
+        push(2, true);
+        push(2, false);
+
+        let page_ids = |batch: Vec<(QueueRequest, InFlightRequest<'_>)>| {
+            batch
+                .iter()
+                .map(|(request, _)| request.page_id())
+                .collect::<Vec<_>>()
+        };
+        // Page 4 is a read, so the writes stop there
+        let batch = shared.next_batch(IO_MAX_COALESCED_PAGES).unwrap();
+        assert!(batch.iter().all(|(request, _)| request.is_write()));
+        assert_eq!(page_ids(batch), vec![1, 2, 3]);
+        // The write of page 5 was queued first and the read of page 4 can't join it
+        for expected in [5, 4, 2] {
+            let batch = shared.next_batch(IO_MAX_COALESCED_PAGES).unwrap();
+            assert_eq!(page_ids(batch), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn test_next_batch_stops_at_the_end_of_the_file() {
+        setup_logger();
+        let shared = empty_queue();
+        let last_page = PageAddress::new(1, PageNo::MAX).page_id();
+        let first_page = PageAddress::new(2, 0).page_id();
+        assert_eq!(last_page + 1, first_page);
+        for page_id in [last_page, first_page] {
+            shared
+                .push(request(page_id, true), IoPriority::Foreground)
+                .unwrap();
+        }
+
+        for expected in [last_page, first_page] {
+            let batch = shared.next_batch(IO_MAX_COALESCED_PAGES).unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].0.page_id(), expected);
+        }
+    }
+
+    #[test]
+    fn test_higher_priorities_are_served_first() {
+        setup_logger();
+        let shared = empty_queue();
+        shared
+            .push(request(1, true), IoPriority::Background)
+            .unwrap();
+        shared
+            .push(request(2, false), IoPriority::Prefetch)
+            .unwrap();
+        shared
+            .push(request(3, true), IoPriority::Background)
+            .unwrap();
+        shared
+            .push(request(4, false), IoPriority::Foreground)
+            .unwrap();
+        // A foreground read of page 3 can't overtake the background write before it, so the
+        // write gets its priority, after the foreground read of page 4 scheduled earlier
+        shared
+            .push(request(3, false), IoPriority::Foreground)
+            .unwrap();
+
+        let served: Vec<_> = std::iter::from_fn(|| shared.try_next_request())
+            .map(|(request, _)| request.page_id())
+            .collect();
+        assert_eq!(served, vec![4, 3, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_old_requests_are_promoted() {
+        setup_logger();
+        let shared = empty_queue();
+        shared
+            .push(request(1, true), IoPriority::Background)
+            .unwrap();
+        shared
+            .push(request(2, false), IoPriority::Foreground)
+            .unwrap();
+        // The background write has been waiting for long enough to be served as foreground
+        shared.queue.lock().unwrap().requests.get_mut(&1).unwrap()[0].enqueued_at -=
+            IO_PRIORITY_AGING * 2;
+
+        let (request, _) = shared.try_next_request().unwrap();
+        assert_eq!(request.page_id(), 1);
+    }
+
+    #[test]
+    fn test_disk_scheduler_coalesces_file_io() {
+        setup_logger();
+        let path =
+            std::env::temp_dir().join(format!("maridbel-{}-coalesced-io.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scheduler = DiskScheduler::new(DiskManager::new(FileBackend::create(&path).unwrap()));
+
+        let writes: Vec<_> = (0..64)
+            .map(|page_id| {
+                scheduler
+                    .schedule_write(
+                        page_id,
+                        frame_filled_with(page_id as u8 + 1),
+                        IoPriority::Foreground,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        // The last page is past the end of the file and reads as an empty page
+        let reads: Vec<_> = (0..65)
+            .map(|page_id| {
+                let target = frame_filled_with(0xff);
+                let read = scheduler
+                    .schedule_read(page_id, target.clone(), IoPriority::Foreground)
+                    .unwrap();
+                (page_id, target, read)
+            })
+            .collect();
+
+        for write in writes {
+            wait_for(write).unwrap();
+        }
+        for (page_id, target, read) in reads {
+            wait_for(read).unwrap();
+            let expected = if page_id == 64 { 0 } else { page_id as u8 + 1 };
+            assert!(target.read().unwrap().data.iter().all(|b| *b == expected));
+        }
+
+        drop(scheduler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Fails the reads of `failing_page` with `kind`, `n_failures` times
+    struct FlakyDisk {
+        inner: MemoryBackend,
+        failing_page: PageNo,
+        kind: std::io::ErrorKind,
+        n_failures: Mutex<usize>,
+    }
+
+    impl StorageBackend for FlakyDisk {
+        fn page_size(&self) -> usize {
+            self.inner.page_size()
+        }
+
+        fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+            let mut n_failures = self.n_failures.lock().unwrap();
+            if page_no == self.failing_page && *n_failures > 0 {
+                *n_failures -= 1;
+                return Err(std::io::Error::from(self.kind));
+            }
+            self.inner.read_page(page_no, buffer)
+        }
+
+        fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+            self.inner.write_page(page_no, data)
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+
+        fn len(&self) -> std::io::Result<u64> {
+            self.inner.len()
+        }
+
+        fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+            self.inner.truncate(n_pages)
+        }
+
+        fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+            self.inner.allocate(n_pages)
+        }
+    }
+
+    fn flaky_disk(kind: std::io::ErrorKind, n_failures: usize) -> DiskManager {
+        DiskManager::new(FlakyDisk {
+            inner: MemoryBackend::from(vec![0u8; 2 * PAGE_SIZE]),
+            failing_page: 1,
+            kind,
+            n_failures: Mutex::new(n_failures),
+        })
+    }
+
+    #[test]
+    fn test_disk_scheduler_survives_io_errors() {
+        setup_logger();
+        let scheduler = DiskScheduler::new(flaky_disk(std::io::ErrorKind::PermissionDenied, 1));
+
+        assert!(matches!(
+            scheduler.read_page(1, frame_filled_with(0), IoPriority::Foreground),
+            Err(ScheduleError::IOError(_))
+        ));
+        // The worker is still alive and serves the next requests
+        scheduler
+            .write_page(0, frame_filled_with(7), IoPriority::Foreground)
+            .unwrap();
+        let target = frame_filled_with(0);
+        scheduler
+            .read_page(0, target.clone(), IoPriority::Foreground)
+            .unwrap();
+        assert!(target.read().unwrap().data.iter().all(|byte| *byte == 7));
+    }
+
+    #[test]
+    fn test_disk_scheduler_retries_transient_errors() {
+        setup_logger();
+        let retry_policy = RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::ZERO,
+        };
+
+        let scheduler = DiskScheduler::with_config(
+            flaky_disk(std::io::ErrorKind::TimedOut, 2),
+            1,
+            retry_policy,
+        );
+        assert!(scheduler
+            .read_page(1, frame_filled_with(0), IoPriority::Foreground)
+            .is_ok());
+
+        let scheduler = DiskScheduler::with_config(
+            flaky_disk(std::io::ErrorKind::TimedOut, 3),
+            1,
+            retry_policy,
+        );
+        assert!(matches!(
+            scheduler.read_page(1, frame_filled_with(0), IoPriority::Foreground),
+            Err(ScheduleError::IOError(err)) if err.kind() == std::io::ErrorKind::TimedOut
+        ));
+    }
 }
@@ -0,0 +1,300 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use super::storage_backend::StorageBackend;
+use crate::storage::PageNo;
+
+/// Identifies a valid directory page in the double-write region
+const DIRECTORY_MAGIC: &[u8; 8] = b"MDBDBLWR";
+/// magic (64bit) + number of entries (32bit) + checksum of the entries (32bit)
+const DIRECTORY_HEADER_SIZE: usize = 16;
+/// page number (32bit) + checksum of the page (32bit)
+const ENTRY_SIZE: usize = 8;
+
+/// Protects the pages of a data file against torn writes, where a crash in the middle of a page
+/// write leaves part of the old page and part of the new one on disk.
+///
+/// Pages are first written to a separate region and synced, then written in place and synced.
+/// The region starts with a directory page listing the pages it holds and their checksums, the
+/// copies follow it. When the data file is opened, a page that doesn't match its copy was torn
+/// and is written again from the copy. Writes are serialized, so the region always holds the last
+/// pages written and never a version older than the one in place.
+pub struct DoubleWriteBackend<B: StorageBackend> {
+    inner: B,
+    region: Box<dyn StorageBackend>,
+    /// Held while a batch goes through the region
+    batch: Mutex<()>,
+}
+
+impl<B: StorageBackend> DoubleWriteBackend<B> {
+    /// Wraps the data file, repairing the pages torn by the last crash from the region. Both must
+    /// have the same page size.
+    pub fn new(inner: B, region: impl StorageBackend + 'static) -> std::io::Result<Self> {
+        if inner.page_size() != region.page_size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The double-write region must have the page size of the data file",
+            ));
+        }
+        let backend = DoubleWriteBackend {
+            inner,
+            region: Box::new(region),
+            batch: Mutex::new(()),
+        };
+        backend.recover()?;
+        Ok(backend)
+    }
+
+    fn recover(&self) -> std::io::Result<()> {
+        let mut directory = vec![0u8; self.page_size()];
+        match self.region.read_page(0, &mut directory) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let Some(entries) = read_directory(&directory) else {
+            // The crash happened before the region was synced, so nothing was written in place
+            return Ok(());
+        };
+
+        let mut copy = vec![0u8; self.page_size()];
+        let mut page = vec![0u8; self.page_size()];
+        let mut n_repaired_pages = 0;
+        for (region_page_no, (page_no, page_checksum)) in (1..).zip(entries) {
+            self.region.read_page(region_page_no, &mut copy)?;
+            if checksum(&copy) != page_checksum {
+                return Ok(());
+            }
+            let in_place = match self.inner.read_page(page_no, &mut page) {
+                Ok(()) => checksum(&page) == page_checksum,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => false,
+                Err(err) => return Err(err),
+            };
+            if !in_place {
+                log::warn!(
+                    "Repairing torn page {page_no} of {:?} from the double-write buffer",
+                    self.inner.path()
+                );
+                self.inner.write_page(page_no, &copy)?;
+                n_repaired_pages += 1;
+            }
+        }
+        if n_repaired_pages > 0 {
+            self.inner.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pages to the region and syncs it, then writes them in place and syncs them
+    fn write_batch(&self, first_page_no: PageNo, data: &[&[u8]]) -> std::io::Result<()> {
+        let _batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+        let directory = directory_page(self.page_size(), first_page_no, data);
+        self.region.write_pages(1, data)?;
+        self.region.write_page(0, &directory)?;
+        self.region.sync()?;
+        self.inner.write_pages(first_page_no, data)?;
+        self.inner.sync()
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for DoubleWriteBackend<B> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_page(page_no, buffer)
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        self.write_batch(page_no, &[data])
+    }
+
+    fn read_pages(&self, first_page_no: PageNo, buffers: &mut [&mut [u8]]) -> std::io::Result<()> {
+        self.inner.read_pages(first_page_no, buffers)
+    }
+
+    fn write_pages(&self, first_page_no: PageNo, data: &[&[u8]]) -> std::io::Result<()> {
+        let max_batch_pages = max_batch_pages(self.page_size());
+        for (batch, chunk) in data.chunks(max_batch_pages).enumerate() {
+            let batch_page_no = first_page_no + (batch * max_batch_pages) as PageNo;
+            self.write_batch(batch_page_no, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        // The copies must not bring back the pages past the new end when the file is opened again
+        let _batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+        self.region.truncate(0)?;
+        self.region.sync()?;
+        self.inner.truncate(n_pages)
+    }
+
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        self.inner.allocate(n_pages)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.inner.path()
+    }
+
+    fn max_pages(&self) -> Option<u64> {
+        self.inner.max_pages()
+    }
+}
+
+/// Where the double-write region of the data file at `path` is kept
+pub fn double_write_path(path: &Path) -> PathBuf {
+    let mut region_path = path.as_os_str().to_owned();
+    region_path.push(".dblwr");
+    region_path.into()
+}
+
+/// How many pages fit in the region at a time. Larger writes go through it in several rounds.
+fn max_batch_pages(page_size: usize) -> usize {
+    (page_size - DIRECTORY_HEADER_SIZE) / ENTRY_SIZE
+}
+
+fn directory_page(page_size: usize, first_page_no: PageNo, data: &[&[u8]]) -> Vec<u8> {
+    let mut directory = vec![0u8; page_size];
+    let entries_end = DIRECTORY_HEADER_SIZE + data.len() * ENTRY_SIZE;
+    for (page_no, (page, entry)) in (first_page_no..).zip(
+        data.iter()
+            .zip(directory[DIRECTORY_HEADER_SIZE..entries_end].chunks_exact_mut(ENTRY_SIZE)),
+    ) {
+        entry[..4].copy_from_slice(&page_no.to_le_bytes());
+        entry[4..].copy_from_slice(&checksum(page).to_le_bytes());
+    }
+    let entries_checksum = checksum(&directory[DIRECTORY_HEADER_SIZE..entries_end]);
+    directory[..8].copy_from_slice(DIRECTORY_MAGIC);
+    directory[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    directory[12..16].copy_from_slice(&entries_checksum.to_le_bytes());
+    directory
+}
+
+/// The page numbers and checksums listed in the directory. None if it was never written
+/// completely.
+fn read_directory(directory: &[u8]) -> Option<Vec<(PageNo, u32)>> {
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    if &directory[..8] != DIRECTORY_MAGIC {
+        return None;
+    }
+    let n_entries = read_u32(&directory[8..12]) as usize;
+    if n_entries > max_batch_pages(directory.len()) {
+        return None;
+    }
+    let entries = &directory[DIRECTORY_HEADER_SIZE..DIRECTORY_HEADER_SIZE + n_entries * ENTRY_SIZE];
+    if checksum(entries) != read_u32(&directory[12..16]) {
+        return None;
+    }
+    Some(
+        entries
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| (read_u32(&entry[..4]), read_u32(&entry[4..])))
+            .collect(),
+    )
+}
+
+/// CRC-32 (IEEE)
+fn checksum(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0u32, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::disk::FaultInjectionBackend;
+    use crate::storage::MemoryBackend;
+    use std::sync::Arc;
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    fn read(backend: &impl StorageBackend, page_no: PageNo) -> Vec<u8> {
+        let mut buffer = page(0);
+        backend.read_page(page_no, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_torn_pages_are_repaired() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let region = Arc::new(MemoryBackend::new());
+        let data_file = FaultInjectionBackend::new(disk.clone());
+        let injector = data_file.injector();
+        let backend = DoubleWriteBackend::new(data_file, region.clone()).unwrap();
+
+        backend.write_pages(0, &[&page(1), &page(1)]).unwrap();
+        injector.tear_nth_write(2, 512);
+        backend.write_pages(0, &[&page(2), &page(2)]).unwrap();
+        injector.crash();
+        assert_eq!(
+            &read(&disk, 1)[..1024],
+            &[&page(2)[..512], &page(1)[..512]].concat()
+        );
+
+        let backend = DoubleWriteBackend::new(disk.clone(), region).unwrap();
+        assert_eq!(read(&backend, 0), page(2));
+        assert_eq!(read(&backend, 1), page(2));
+    }
+
+    #[test]
+    fn test_incomplete_copies_are_ignored() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let region = Arc::new(MemoryBackend::new());
+        let backend = DoubleWriteBackend::new(disk.clone(), region.clone()).unwrap();
+        backend.write_page(3, &page(1)).unwrap();
+
+        // The copy is damaged, so the page was never written in place and is kept as it is
+        region.write_page(1, &page(9)).unwrap();
+        disk.write_page(3, &page(4)).unwrap();
+        let backend = DoubleWriteBackend::new(disk.clone(), region.clone()).unwrap();
+        assert_eq!(read(&backend, 3), page(4));
+
+        // Truncating the data file forgets the copies
+        backend.write_page(3, &page(5)).unwrap();
+        backend.truncate(2).unwrap();
+        drop(backend);
+        let backend = DoubleWriteBackend::new(disk, region).unwrap();
+        assert_eq!(backend.len().unwrap(), 2);
+    }
+}
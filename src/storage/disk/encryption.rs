@@ -0,0 +1,354 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+
+use super::storage_backend::StorageBackend;
+use crate::storage::{FileId, PageNo};
+
+/// How many more bytes the pages of an encrypted data file take on disk: session (64bit) + write
+/// sequence (64bit) + authentication tag (128bit)
+pub const ENCRYPTION_TRAILER_SIZE: usize = 32;
+
+/// A 256-bit key to encrypt the pages of a database
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Reads the key from a file holding either the 32 bytes of the key or 64 hex digits
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path)?;
+        if let Ok(bytes) = <[u8; 32]>::try_from(contents.as_slice()) {
+            return Ok(EncryptionKey(bytes));
+        }
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Key files must hold 32 bytes or 64 hex digits",
+            )
+        };
+        let digits = std::str::from_utf8(&contents)
+            .map_err(|_| invalid())?
+            .trim();
+        if digits.len() != 64 || !digits.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, digits) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+}
+
+/// Never prints the key
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Encrypts every page with XChaCha20-Poly1305 before it reaches the wrapped backend, and checks
+/// its authentication tag when it is read back, so tampered pages are detected.
+///
+/// Each page of the wrapped backend holds an encrypted page followed by a trailer of
+/// `ENCRYPTION_TRAILER_SIZE` bytes with what is needed to decrypt it, so its pages are that much
+/// larger than the pages it serves. A page and its trailer are written together, so a crash
+/// never leaves a page without the tag it was written with. The nonce of a page is its address,
+/// a random session id and the number of writes made by the session, so it is never reused. A
+/// page whose trailer is empty was never written and reads as zeroes.
+pub struct EncryptedBackend<B: StorageBackend> {
+    inner: B,
+    cipher: XChaCha20Poly1305,
+    /// Part of the nonce, so data files encrypted with the same key never share nonces
+    file_id: FileId,
+    session: u64,
+    /// Writes made by this session, starting from 1 so an empty trailer is never a valid one
+    n_writes: AtomicU64,
+}
+
+/// What is needed to decrypt a page, stored after it
+#[derive(Clone, Copy)]
+struct Trailer {
+    session: u64,
+    sequence: u64,
+    tag: [u8; 16],
+}
+
+impl<B: StorageBackend> EncryptedBackend<B> {
+    /// The pages of `inner` must be `ENCRYPTION_TRAILER_SIZE` bytes larger than the pages to
+    /// encrypt
+    pub fn new(inner: B, key: &EncryptionKey, file_id: FileId) -> Self {
+        assert!(
+            inner.page_size() > ENCRYPTION_TRAILER_SIZE,
+            "The pages are too small to hold the encryption trailer"
+        );
+        EncryptedBackend {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key.0)),
+            file_id,
+            session: RandomState::new().build_hasher().finish(),
+            n_writes: AtomicU64::new(0),
+        }
+    }
+
+    fn nonce(&self, page_no: PageNo, trailer: &Trailer) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[0..4].copy_from_slice(&self.file_id.to_be_bytes());
+        nonce[4..8].copy_from_slice(&page_no.to_be_bytes());
+        nonce[8..16].copy_from_slice(&trailer.session.to_be_bytes());
+        nonce[16..24].copy_from_slice(&trailer.sequence.to_be_bytes());
+        nonce
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for EncryptedBackend<B> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size() - ENCRYPTION_TRAILER_SIZE
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        let mut page = vec![0u8; self.inner.page_size()];
+        self.inner.read_page(page_no, &mut page)?;
+        let (ciphertext, trailer) = page.split_at(self.page_size());
+        let trailer = Trailer::read(trailer);
+        if trailer.sequence == 0 {
+            buffer.fill(0);
+            return Ok(());
+        }
+        buffer.copy_from_slice(ciphertext);
+        self.cipher
+            .decrypt_in_place_detached(
+                &self.nonce(page_no, &trailer),
+                &[],
+                buffer,
+                Tag::from_slice(&trailer.tag),
+            )
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Page {page_no} of data file {} failed authentication",
+                        self.file_id
+                    ),
+                )
+            })
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        let mut trailer = Trailer {
+            session: self.session,
+            sequence: self.n_writes.fetch_add(1, Ordering::Relaxed) + 1,
+            tag: [0; 16],
+        };
+        let mut page = vec![0u8; self.inner.page_size()];
+        let (ciphertext, trailer_bytes) = page.split_at_mut(self.page_size());
+        ciphertext.copy_from_slice(data);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&self.nonce(page_no, &trailer), &[], ciphertext)
+            .map_err(|_| std::io::Error::other("Could not encrypt the page"))?;
+        trailer.tag.copy_from_slice(&tag);
+        trailer.write(trailer_bytes);
+        self.inner.write_page(page_no, &page)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        self.inner.truncate(n_pages)
+    }
+
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        self.inner.allocate(n_pages)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.inner.path()
+    }
+
+    fn max_pages(&self) -> Option<u64> {
+        self.inner.max_pages()
+    }
+
+    // No raw_fd: IO that bypasses this backend would skip the encryption
+}
+
+impl Trailer {
+    fn read(bytes: &[u8]) -> Self {
+        Trailer {
+            session: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            sequence: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            tag: bytes[16..32].try_into().unwrap(),
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[0..8].copy_from_slice(&self.session.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[16..32].copy_from_slice(&self.tag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::disk::FaultInjectionBackend;
+    use crate::storage::MemoryBackend;
+    use std::sync::Arc;
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey::from_bytes([byte; 32])
+    }
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    /// The wrapped backend of `EncryptedBackend`s serving pages of `page_size` bytes
+    fn disk(page_size: usize) -> Arc<MemoryBackend> {
+        Arc::new(MemoryBackend::new().with_page_size(page_size + ENCRYPTION_TRAILER_SIZE))
+    }
+
+    fn read(backend: &impl StorageBackend, page_no: PageNo) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; backend.page_size()];
+        backend.read_page(page_no, &mut buffer).map(|_| buffer)
+    }
+
+    #[test]
+    fn test_pages_are_encrypted() {
+        setup_logger();
+        let disk = disk(PAGE_SIZE);
+        let backend = EncryptedBackend::new(disk.clone(), &key(1), 0);
+        assert_eq!(backend.page_size(), PAGE_SIZE);
+
+        backend.write_page(0, &page(7)).unwrap();
+        backend.write_page(200, &page(8)).unwrap();
+        assert_eq!(read(&backend, 0).unwrap(), page(7));
+        assert_eq!(read(&backend, 200).unwrap(), page(8));
+        // Allocated but never written pages are empty
+        assert_eq!(read(&backend, 1).unwrap(), page(0));
+        assert_eq!(backend.len().unwrap(), 201);
+        assert!(read(&backend, 201).is_err());
+
+        let ciphertext = read(&disk, 0).unwrap();
+        assert_ne!(ciphertext[..PAGE_SIZE], page(7));
+        // Writing the same data again uses another nonce
+        backend.write_page(0, &page(7)).unwrap();
+        assert_ne!(read(&disk, 0).unwrap(), ciphertext);
+
+        // The trailers survive reopening the data file
+        let backend = EncryptedBackend::new(disk, &key(1), 0);
+        assert_eq!(read(&backend, 0).unwrap(), page(7));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        setup_logger();
+        let disk = disk(PAGE_SIZE);
+        let backend = EncryptedBackend::new(disk.clone(), &key(1), 0);
+        backend.write_page(0, &page(7)).unwrap();
+        backend.write_page(1, &page(7)).unwrap();
+
+        let mut ciphertext = read(&disk, 0).unwrap();
+        ciphertext[100] ^= 1;
+        disk.write_page(0, &ciphertext).unwrap();
+        let err = read(&backend, 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Pages moved around are detected too
+        disk.write_page(0, &read(&disk, 1).unwrap()).unwrap();
+        assert!(read(&backend, 0).is_err());
+
+        let other_key = EncryptedBackend::new(disk.clone(), &key(2), 0);
+        assert!(read(&other_key, 1).is_err());
+
+        // So is a tampered trailer
+        let mut trailer = read(&disk, 1).unwrap();
+        trailer[PAGE_SIZE + 8] ^= 1;
+        disk.write_page(1, &trailer).unwrap();
+        assert!(read(&backend, 1).is_err());
+    }
+
+    #[test]
+    fn test_truncate_forgets_pages() {
+        setup_logger();
+        let backend = EncryptedBackend::new(disk(PAGE_SIZE), &key(1), 0);
+        for page_no in 0..4 {
+            backend.write_page(page_no, &page(1)).unwrap();
+        }
+        backend.truncate(2).unwrap();
+        assert_eq!(backend.len().unwrap(), 2);
+
+        backend.allocate(4).unwrap();
+        assert_eq!(read(&backend, 1).unwrap(), page(1));
+        assert_eq!(read(&backend, 3).unwrap(), page(0));
+    }
+
+    #[test]
+    fn test_crash_keeps_pages_readable() {
+        setup_logger();
+        let disk = disk(PAGE_SIZE);
+        let faulty = FaultInjectionBackend::new(disk.clone());
+        let injector = faulty.injector();
+        let backend = EncryptedBackend::new(faulty, &key(1), 0);
+
+        backend.write_page(0, &page(1)).unwrap();
+        backend.sync().unwrap();
+        // A page and its tag reach the disk in a single write
+        let n_writes = injector.n_writes();
+        backend.write_page(0, &page(2)).unwrap();
+        backend.write_page(1, &page(2)).unwrap();
+        assert_eq!(injector.n_writes(), n_writes + 2);
+        injector.crash();
+
+        let backend = EncryptedBackend::new(disk, &key(1), 0);
+        assert_eq!(read(&backend, 0).unwrap(), page(1));
+    }
+
+    #[test]
+    fn test_key_file() {
+        setup_logger();
+        let path = std::env::temp_dir().join(format!("maridbel-{}-key", std::process::id()));
+
+        std::fs::write(&path, format!("{}\n", "ab".repeat(32))).unwrap();
+        assert_eq!(EncryptionKey::from_file(&path).unwrap().0, [0xab; 32]);
+        std::fs::write(&path, [3u8; 32]).unwrap();
+        assert_eq!(EncryptionKey::from_file(&path).unwrap().0, [3; 32]);
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(EncryptionKey::from_file(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trailer_follows_the_page_size() {
+        setup_logger();
+        let page_size = 4 * PAGE_SIZE;
+        let disk = disk(page_size);
+        let backend = EncryptedBackend::new(disk.clone(), &key(1), 0);
+        assert_eq!(backend.page_size(), page_size);
+
+        backend.write_page(3, &vec![3u8; page_size]).unwrap();
+        assert_eq!(disk.len().unwrap(), 4);
+        let mut buffer = vec![0u8; page_size];
+        backend.read_page(3, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![3u8; page_size]);
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use super::storage_backend::StorageBackend;
+use crate::storage::PageNo;
+
+/// Wraps a backend to make it fail on purpose, so tests can go through the error paths of the
+/// disk scheduler, the buffer pool and recovery deterministically.
+///
+/// Writes are kept in memory until `sync`, like an OS page cache, so a simulated crash loses
+/// every write that was never synced. After a crash every operation fails. The durable state is
+/// whatever reached the wrapped backend, which the test can open again.
+pub struct FaultInjectionBackend<B: StorageBackend> {
+    inner: Arc<B>,
+    injector: FaultInjector,
+}
+
+/// Scripts the failures of a `FaultInjectionBackend`. It can be cloned and kept by the test
+/// after the backend is handed to a database.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    n_reads: u64,
+    n_writes: u64,
+    /// Faults keyed by the number of the read or write they hit
+    read_faults: HashMap<u64, Fault>,
+    write_faults: HashMap<u64, Fault>,
+    latency: Duration,
+    /// Growing past this many pages fails with ENOSPC
+    capacity: Option<u64>,
+    crashed: bool,
+    /// Written but not synced pages, in page order so they are synced sequentially
+    unsynced: BTreeMap<PageNo, Box<[u8]>>,
+}
+
+#[derive(Clone, Copy)]
+enum Fault {
+    /// Fails with EIO and nothing is written
+    Error,
+    /// Only the first bytes of the page are written and the write reports success
+    Torn(usize),
+}
+
+impl<B: StorageBackend> FaultInjectionBackend<B> {
+    pub fn new(inner: Arc<B>) -> Self {
+        FaultInjectionBackend {
+            inner,
+            injector: FaultInjector::default(),
+        }
+    }
+
+    pub fn injector(&self) -> FaultInjector {
+        self.injector.clone()
+    }
+}
+
+impl FaultInjector {
+    /// The `n`th read from now fails with EIO. The next read is the first one.
+    pub fn fail_nth_read(&self, n: u64) {
+        let mut state = self.lock();
+        let n = state.n_reads + n;
+        state.read_faults.insert(n, Fault::Error);
+    }
+
+    /// The `n`th write from now fails with EIO. The next write is the first one.
+    pub fn fail_nth_write(&self, n: u64) {
+        let mut state = self.lock();
+        let n = state.n_writes + n;
+        state.write_faults.insert(n, Fault::Error);
+    }
+
+    /// The `n`th write from now only persists the first `n_bytes` of the page, but reports
+    /// success
+    pub fn tear_nth_write(&self, n: u64, n_bytes: usize) {
+        let mut state = self.lock();
+        let n = state.n_writes + n;
+        state.write_faults.insert(n, Fault::Torn(n_bytes));
+    }
+
+    /// Every operation sleeps for this long before doing anything
+    pub fn set_latency(&self, latency: Duration) {
+        self.lock().latency = latency;
+    }
+
+    /// Writing or allocating pages past the first `n_pages` fails with ENOSPC, as if the disk
+    /// was full
+    pub fn set_capacity(&self, n_pages: u64) {
+        self.lock().capacity = Some(n_pages);
+    }
+
+    /// Drops every write that was not synced and makes every later operation fail, as if the
+    /// machine lost power
+    pub fn crash(&self) {
+        let mut state = self.lock();
+        state.crashed = true;
+        state.unsynced.clear();
+    }
+
+    pub fn n_writes(&self) -> u64 {
+        self.lock().n_writes
+    }
+
+    /// The number of written pages that would be lost in a crash
+    pub fn n_unsynced_pages(&self) -> usize {
+        self.lock().unsynced.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sleeps for the latency and fails if the machine crashed. The state is not locked while
+    /// sleeping so concurrent operations overlap like they would on a real disk.
+    fn begin_operation(&self) -> std::io::Result<MutexGuard<'_, FaultState>> {
+        let latency = self.lock().latency;
+        if !latency.is_zero() {
+            std::thread::sleep(latency);
+        }
+        let state = self.lock();
+        if state.crashed {
+            return Err(injected_error());
+        }
+        Ok(state)
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for FaultInjectionBackend<B> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        let mut state = self.injector.begin_operation()?;
+        state.n_reads += 1;
+        let n_reads = state.n_reads;
+        if state.read_faults.remove(&n_reads).is_some() {
+            return Err(injected_error());
+        }
+
+        match state.unsynced.get(&page_no) {
+            Some(page) => {
+                buffer.copy_from_slice(page);
+                Ok(())
+            }
+            None => self.inner.read_page(page_no, buffer),
+        }
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        let mut state = self.injector.begin_operation()?;
+        state.n_writes += 1;
+        let n_writes = state.n_writes;
+
+        if state
+            .capacity
+            .is_some_and(|capacity| page_no as u64 >= capacity)
+        {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        let page: Box<[u8]> = match state.write_faults.remove(&n_writes) {
+            None => data.into(),
+            Some(Fault::Error) => return Err(injected_error()),
+            Some(Fault::Torn(n_bytes)) => {
+                let mut page = match state.unsynced.get(&page_no) {
+                    Some(page) => page.clone(),
+                    None => {
+                        let mut page = vec![0u8; data.len()].into_boxed_slice();
+                        // The page may not exist yet, in which case the rest of it is zeroed
+                        let _ = self.inner.read_page(page_no, &mut page);
+                        page
+                    }
+                };
+                let n_bytes = n_bytes.min(data.len());
+                page[..n_bytes].copy_from_slice(&data[..n_bytes]);
+                page
+            }
+        };
+        state.unsynced.insert(page_no, page);
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        let mut state = self.injector.begin_operation()?;
+        for (page_no, page) in std::mem::take(&mut state.unsynced) {
+            self.inner.write_page(page_no, &page)?;
+        }
+        self.inner.sync()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        let state = self.injector.begin_operation()?;
+        let unsynced_len = state
+            .unsynced
+            .last_key_value()
+            .map_or(0, |(page_no, _)| *page_no as u64 + 1);
+        Ok(self.inner.len()?.max(unsynced_len))
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        let mut state = self.injector.begin_operation()?;
+        state
+            .unsynced
+            .retain(|page_no, _| (*page_no as u64) < n_pages);
+        self.inner.truncate(n_pages)
+    }
+
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        let state = self.injector.begin_operation()?;
+        if state.capacity.is_some_and(|capacity| n_pages > capacity) {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        self.inner.allocate(n_pages)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.inner.path()
+    }
+
+    fn max_pages(&self) -> Option<u64> {
+        self.inner.max_pages()
+    }
+}
+
+fn injected_error() -> std::io::Error {
+    std::io::Error::from_raw_os_error(libc::EIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::MemoryBackend;
+    use std::time::Instant;
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    fn read(backend: &impl StorageBackend, page_no: PageNo) -> std::io::Result<Vec<u8>> {
+        let mut buffer = page(0);
+        backend.read_page(page_no, &mut buffer).map(|_| buffer)
+    }
+
+    #[test]
+    fn test_fail_nth_write() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.injector().fail_nth_write(2);
+
+        backend.write_page(0, &page(1)).unwrap();
+        let err = backend.write_page(1, &page(1)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        backend.write_page(1, &page(2)).unwrap();
+        assert_eq!(read(&backend, 1).unwrap(), page(2));
+        assert_eq!(backend.injector().n_writes(), 3);
+    }
+
+    #[test]
+    fn test_fail_nth_read() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.write_page(0, &page(1)).unwrap();
+        backend.injector().fail_nth_read(1);
+
+        assert!(read(&backend, 0).is_err());
+        assert_eq!(read(&backend, 0).unwrap(), page(1));
+    }
+
+    #[test]
+    fn test_torn_write() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.write_page(0, &page(1)).unwrap();
+        backend.injector().tear_nth_write(1, 100);
+
+        backend.write_page(0, &page(2)).unwrap();
+        let torn = read(&backend, 0).unwrap();
+        assert!(torn[..100].iter().all(|b| *b == 2));
+        assert!(torn[100..].iter().all(|b| *b == 1));
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced_writes() {
+        setup_logger();
+        let disk = Arc::new(MemoryBackend::new());
+        let backend = FaultInjectionBackend::new(disk.clone());
+        let injector = backend.injector();
+
+        backend.write_page(0, &page(1)).unwrap();
+        backend.sync().unwrap();
+        backend.write_page(0, &page(2)).unwrap();
+        backend.write_page(1, &page(2)).unwrap();
+        assert_eq!(backend.len().unwrap(), 2);
+        assert_eq!(injector.n_unsynced_pages(), 2);
+
+        injector.crash();
+        assert!(backend.write_page(0, &page(3)).is_err());
+        assert!(read(&backend, 0).is_err());
+
+        // After rebooting only the synced data is there
+        assert_eq!(read(&disk, 0).unwrap(), page(1));
+        assert_eq!(disk.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_latency() {
+        setup_logger();
+        let backend = FaultInjectionBackend::new(Arc::new(MemoryBackend::new()));
+        backend.injector().set_latency(Duration::from_millis(20));
+
+        let start = Instant::now();
+        backend.write_page(0, &page(1)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
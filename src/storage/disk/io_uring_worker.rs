@@ -0,0 +1,319 @@
+use std::os::fd::RawFd;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use oneshot::OneshotChannelSender;
+
+use super::disk_manager::DiskManager;
+use super::disk_scheduler::{
+    complete_request, serve_request, InFlightRequest, QueueRequest, RetryPolicy, ScheduleResult,
+    SharedQueue, WorkerExitGuard,
+};
+use super::storage_backend::page_no_to_file_offset;
+use crate::config::IO_URING_QUEUE_DEPTH;
+use crate::errors::ScheduleError;
+use crate::storage::{Frame, PageAddress, PageId};
+
+/// A request submitted to the kernel and waiting for its completion
+struct PendingIo<'a> {
+    page_id: PageId,
+    /// The data file of the page
+    fd: RawFd,
+    is_read: bool,
+    frame: Arc<RwLock<Frame>>,
+    channel: OneshotChannelSender<ScheduleResult>,
+    /// The kernel reads from or writes into this buffer, it must not be freed until the IO
+    /// completes
+    buffer: Box<[u8]>,
+    n_retries: u32,
+    _in_flight: InFlightRequest<'a>,
+}
+
+/// Serves requests through io_uring, keeping up to IO_URING_QUEUE_DEPTH of them in flight from a
+/// single thread. Fails if io_uring can't be used, in which case the caller falls back to
+/// blocking IO. Pages of tablespaces without a file descriptor are served with blocking IO.
+pub(super) fn run_worker(
+    shared: &SharedQueue,
+    disk_manager: &DiskManager,
+    retry_policy: RetryPolicy,
+) -> std::io::Result<()> {
+    disk_manager.raw_fd(0).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "io_uring needs a file descriptor",
+        )
+    })?;
+    let mut ring = IoUring::new(IO_URING_QUEUE_DEPTH).inspect_err(|err| {
+        log::warn!("Could not set up io_uring, falling back to blocking IO: {err}");
+    })?;
+    log::debug!("DiskScheduler worker using io_uring");
+    serve_with_ring(shared, disk_manager, retry_policy, &mut ring, |ring| {
+        ring.submit_and_wait(1)
+    })
+}
+
+/// Serves requests with the ring until the scheduler shuts down. `submit` submits the queued
+/// entries and waits for at least one completion. If it fails, the requests in flight fail and
+/// the queue is left open for the blocking worker taking over.
+pub(super) fn serve_with_ring(
+    shared: &SharedQueue,
+    disk_manager: &DiskManager,
+    retry_policy: RetryPolicy,
+    ring: &mut IoUring,
+    mut submit: impl FnMut(&IoUring) -> std::io::Result<usize>,
+) -> std::io::Result<()> {
+    let exit_guard = WorkerExitGuard(shared);
+    let mut pending: Vec<Option<PendingIo>> = (0..IO_URING_QUEUE_DEPTH).map(|_| None).collect();
+    let mut n_pending = 0;
+
+    loop {
+        // Fill the free slots, only blocking for a request if there is nothing in flight
+        while n_pending < pending.len() {
+            let next = if n_pending == 0 {
+                shared.next_request()
+            } else {
+                shared.try_next_request()
+            };
+            let Some((request, in_flight)) = next else {
+                break;
+            };
+
+            let slot = pending
+                .iter()
+                .position(Option::is_none)
+                .expect("there is a free slot");
+            // Growing the file blocks, but only happens once per extent. Files without a
+            // descriptor, and allocation errors, are left to the blocking path.
+            let page_id = request.page_id();
+            let fd = disk_manager
+                .raw_fd(PageAddress::of(page_id).file_id)
+                .filter(|_| disk_manager.allocate_page(page_id).is_ok());
+            let Some(fd) = fd else {
+                serve_request(0, disk_manager, retry_policy, request);
+                drop(in_flight);
+                continue;
+            };
+            let mut io = PendingIo::new(request, in_flight, fd);
+            push(ring, slot, &mut io);
+            pending[slot] = Some(io);
+            n_pending += 1;
+        }
+        if n_pending == 0 {
+            // Shut down and nothing left to serve
+            return Ok(());
+        }
+
+        match submit(ring) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                log::error!("io_uring submission failed: {err}");
+                abandon(&mut pending);
+                // Not a real exit, the caller falls back to blocking IO on the same queue
+                std::mem::forget(exit_guard);
+                return Err(err);
+            }
+        }
+
+        let completions: Vec<_> = ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+        for (slot, result) in completions {
+            let mut io = pending[slot]
+                .take()
+                .expect("completion for a request that was not submitted");
+
+            if result < 0 {
+                let err = std::io::Error::from_raw_os_error(-result);
+                match retry_policy.on_error(io.page_id, err, io.n_retries) {
+                    // Retried right away, sleeping here would stall every other request
+                    Ok(()) => {
+                        io.n_retries += 1;
+                        push(ring, slot, &mut io);
+                        pending[slot] = Some(io);
+                    }
+                    Err(err) => {
+                        n_pending -= 1;
+                        io.finish(Err(err));
+                    }
+                }
+                continue;
+            }
+
+            n_pending -= 1;
+            let result = if (result as usize) < io.buffer.len() {
+                // Short reads happen past the end of the file and short writes when the disk is
+                // full. The blocking path knows how to deal with both.
+                io.complete_blocking(disk_manager)
+            } else if !io.is_read {
+                disk_manager
+                    .complete_page_write(io.page_id)
+                    .map_err(ScheduleError::from)
+            } else {
+                Ok(())
+            };
+            io.finish(result);
+        }
+    }
+}
+
+impl<'a> PendingIo<'a> {
+    fn new(request: QueueRequest, in_flight: InFlightRequest<'a>, fd: RawFd) -> Self {
+        let (page_id, is_read, frame, channel) = match request {
+            QueueRequest::Read {
+                page_id,
+                buffer,
+                channel,
+            } => (page_id, true, buffer, channel),
+            QueueRequest::Write {
+                page_id,
+                data,
+                channel,
+            } => (page_id, false, data, channel),
+        };
+        let buffer = {
+            let frame = frame.read().unwrap_or_else(PoisonError::into_inner);
+            if is_read {
+                vec![0u8; frame.data.len()].into_boxed_slice()
+            } else {
+                frame.data.clone()
+            }
+        };
+
+        PendingIo {
+            page_id,
+            fd,
+            is_read,
+            frame,
+            channel,
+            buffer,
+            n_retries: 0,
+            _in_flight: in_flight,
+        }
+    }
+
+    fn complete_blocking(&mut self, disk_manager: &DiskManager) -> ScheduleResult {
+        let result = if self.is_read {
+            disk_manager.read_page(self.page_id, &mut self.buffer)
+        } else {
+            disk_manager.write_page(self.page_id, &self.buffer)
+        };
+        result.map_err(ScheduleError::from)
+    }
+
+    fn finish(self, result: ScheduleResult) {
+        if self.is_read && result.is_ok() {
+            self.frame
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .data
+                .copy_from_slice(&self.buffer);
+        }
+        complete_request(self.page_id, self.channel, result);
+    }
+}
+
+fn push(ring: &mut IoUring, slot: usize, io: &mut PendingIo) {
+    let fd = io.fd;
+    let offset = page_no_to_file_offset(PageAddress::of(io.page_id).page_no, io.buffer.len());
+    let entry: squeue::Entry = if io.is_read {
+        opcode::Read::new(
+            types::Fd(fd),
+            io.buffer.as_mut_ptr(),
+            io.buffer.len() as u32,
+        )
+        .offset(offset)
+        .build()
+    } else {
+        opcode::Write::new(types::Fd(fd), io.buffer.as_ptr(), io.buffer.len() as u32)
+            .offset(offset)
+            .build()
+    };
+
+    // SAFETY: the buffer lives in the pending slot until its completion is reaped, and the
+    // submission queue has as many entries as there are slots
+    unsafe {
+        ring.submission()
+            .push(&entry.user_data(slot as u64))
+            .expect("the submission queue is never full");
+    }
+}
+
+/// Fails every pending request. Their buffers are leaked because the kernel may still be using
+/// them.
+fn abandon(pending: &mut [Option<PendingIo>]) {
+    for io in pending.iter_mut().filter_map(Option::take) {
+        let PendingIo {
+            page_id,
+            channel,
+            buffer,
+            ..
+        } = io;
+        std::mem::forget(buffer);
+        complete_request(
+            page_id,
+            channel,
+            Err(ScheduleError::IOError(std::io::Error::other(
+                "io_uring submission failed",
+            ))),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PAGE_SIZE;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::disk::disk_scheduler::{wait_for, DiskScheduler, IoPriority};
+    use crate::storage::FileBackend;
+
+    #[test]
+    fn test_io_uring_worker_reads_and_writes() {
+        setup_logger();
+        let path =
+            std::env::temp_dir().join(format!("maridbel-{}-io-uring.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scheduler = DiskScheduler::with_config(
+            DiskManager::new(FileBackend::create(&path).unwrap()),
+            1,
+            RetryPolicy::default(),
+        );
+
+        let frame_with = |byte: u8| {
+            Arc::new(RwLock::new(Frame::new(
+                vec![byte; PAGE_SIZE].into_boxed_slice(),
+            )))
+        };
+        let writes: Vec<_> = (0..128)
+            .map(|page_id| {
+                scheduler
+                    .schedule_write(page_id, frame_with(page_id as u8), IoPriority::Foreground)
+                    .unwrap()
+            })
+            .collect();
+        let reads: Vec<_> = (0..129)
+            .map(|page_id| {
+                let target = frame_with(0xff);
+                let read = scheduler
+                    .schedule_read(page_id, target.clone(), IoPriority::Foreground)
+                    .unwrap();
+                (page_id, target, read)
+            })
+            .collect();
+
+        for write in writes {
+            wait_for(write).unwrap();
+        }
+        for (page_id, target, read) in reads {
+            wait_for(read).unwrap();
+            // The last page is past the end of the file and reads as an empty page
+            let expected = if page_id == 128 { 0 } else { page_id as u8 };
+            assert!(target.read().unwrap().data.iter().all(|b| *b == expected));
+        }
+
+        drop(scheduler);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{PoisonError, RwLock};
+
+use super::storage_backend::{page_no_to_file_offset, FileBackend, StorageBackend};
+use crate::storage::PageNo;
+
+/// Pages stored in a file that is mapped in memory. Reads and writes are plain memory copies and
+/// the kernel decides when the pages reach the disk, until `sync` forces them to.
+pub struct MmapBackend {
+    file: FileBackend,
+    /// Remapped whenever the file grows or shrinks
+    mapping: RwLock<Mapping>,
+}
+
+/// The whole file mapped in memory. Empty files are not mapped at all.
+struct Mapping {
+    ptr: Option<NonNull<u8>>,
+    len: usize,
+}
+
+// SAFETY: the mapping is only accessed through MmapBackend, which synchronizes remapping with a
+// lock and never lets two operations touch the same page at the same time
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl MmapBackend {
+    /// Opens the database file, or creates it if it doesn't exist. Existing data is preserved.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        MmapBackend::from_file(FileBackend::open(path)?)
+    }
+
+    /// Opens an existing database file without write permission, see
+    /// `FileBackend::open_read_only`. Writing pages fails.
+    pub fn open_read_only(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        MmapBackend::from_file(FileBackend::open_read_only(path)?)
+    }
+
+    /// Creates a new database file. Fails if the file already exists.
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        MmapBackend::from_file(FileBackend::create(path)?)
+    }
+
+    /// Reads and writes pages of `page_size` bytes instead of `PAGE_SIZE`
+    pub fn with_page_size(self, page_size: usize) -> Self {
+        MmapBackend {
+            file: self.file.with_page_size(page_size),
+            ..self
+        }
+    }
+
+    fn from_file(file: FileBackend) -> std::io::Result<Self> {
+        let mapping = Mapping::new(&file, file.byte_len()? as usize)?;
+        Ok(MmapBackend {
+            file,
+            mapping: RwLock::new(mapping),
+        })
+    }
+
+    /// Maps the file again with its current size. Nobody can access the mapping meanwhile since
+    /// the caller holds its lock.
+    fn remap(&self, mapping: &mut Mapping) -> std::io::Result<()> {
+        mapping.unmap();
+        *mapping = Mapping::new(&self.file, self.file.byte_len()? as usize)?;
+        Ok(())
+    }
+}
+
+impl StorageBackend for MmapBackend {
+    fn page_size(&self) -> usize {
+        self.file.page_size()
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        let mapping = self.mapping.read().unwrap_or_else(PoisonError::into_inner);
+        let page = mapping
+            .page(
+                page_no_to_file_offset(page_no, self.page_size()),
+                buffer.len(),
+            )
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        // SAFETY: the page is inside the mapping, which can't be remapped while we hold the lock
+        unsafe {
+            std::ptr::copy_nonoverlapping(page.as_ptr(), buffer.as_mut_ptr(), buffer.len());
+        }
+        Ok(())
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        // The mapping can't be written, that would crash the process
+        if self.file.is_read_only() {
+            return Err(std::io::Error::from_raw_os_error(libc::EBADF));
+        }
+        let offset = page_no_to_file_offset(page_no, self.page_size());
+        let end = offset as usize + data.len();
+        if self
+            .mapping
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len
+            < end
+        {
+            self.allocate(end.div_ceil(self.page_size()) as u64)?;
+        }
+
+        // A truncate between growing the mapping and taking the lock can shrink it again
+        let mapping = self.mapping.read().unwrap_or_else(PoisonError::into_inner);
+        let page = mapping
+            .page(offset, data.len())
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        // SAFETY: the page is inside the mapping, which can't be remapped while we hold the lock,
+        // and nobody else touches the page while it is being written
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), page.as_ptr(), data.len());
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        let mapping = self.mapping.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(ptr) = mapping.ptr {
+            // SAFETY: the whole mapping is synced while we hold the lock
+            if unsafe { libc::msync(ptr.as_ptr().cast(), mapping.len, libc::MS_SYNC) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        self.file.sync()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.file.len()
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        let mut mapping = self.mapping.write().unwrap_or_else(PoisonError::into_inner);
+        self.file.truncate(n_pages)?;
+        self.remap(&mut mapping)
+    }
+
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        let mut mapping = self.mapping.write().unwrap_or_else(PoisonError::into_inner);
+        if mapping.len < n_pages as usize * self.page_size() {
+            self.file.allocate(n_pages)?;
+            self.remap(&mut mapping)?;
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.file.path()
+    }
+}
+
+impl Mapping {
+    fn new(file: &FileBackend, len: usize) -> std::io::Result<Self> {
+        if len == 0 {
+            return Ok(Mapping { ptr: None, len });
+        }
+        let protection = if file.is_read_only() {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        // SAFETY: the file is open with the permissions of the mapping and at least `len` bytes
+        // long
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                protection,
+                libc::MAP_SHARED,
+                file.file().as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: NonNull::new(ptr.cast()),
+            len,
+        })
+    }
+
+    /// The page at the file offset as a pointer into the mapping, or None if it is past the end
+    /// of it
+    fn page(&self, offset: u64, len: usize) -> Option<NonNull<u8>> {
+        let offset = offset as usize;
+        if offset + len > self.len {
+            return None;
+        }
+        // SAFETY: the offset is inside the mapping
+        self.ptr.map(|ptr| unsafe { ptr.add(offset) })
+    }
+
+    fn unmap(&mut self) {
+        if let Some(ptr) = self.ptr.take() {
+            // SAFETY: the pointer and length come from a successful mmap
+            unsafe {
+                libc::munmap(ptr.as_ptr().cast(), self.len);
+            }
+        }
+        self.len = 0;
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
@@ -0,0 +1,554 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::config::PAGE_SIZE;
+use crate::storage::PageNo;
+
+/// Linux refuses vectored IO with more buffers than this (IOV_MAX)
+const MAX_IO_VECTORS: usize = 1024;
+
+/// Where the pages of a data file are stored. Pages are addressed by their number in the file and
+/// every operation takes `&self`, so several disk workers can use the backend at the same time.
+/// Concurrent operations on the same page never happen, the disk scheduler takes care of that.
+pub trait StorageBackend: Send + Sync {
+    /// The size of every page, and of the buffers pages are read into and written from
+    fn page_size(&self) -> usize;
+
+    /// Reads the page into the buffer. Fails with `UnexpectedEof` if the page is past the end of
+    /// the storage.
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()>;
+
+    /// Writes the page, growing the storage if the page is past the end of it
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()>;
+
+    /// Reads adjacent pages starting at `first_page_no`
+    fn read_pages(&self, first_page_no: PageNo, buffers: &mut [&mut [u8]]) -> std::io::Result<()> {
+        for (page_no, buffer) in (first_page_no..).zip(buffers.iter_mut()) {
+            self.read_page(page_no, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes adjacent pages starting at `first_page_no`
+    fn write_pages(&self, first_page_no: PageNo, data: &[&[u8]]) -> std::io::Result<()> {
+        for (page_no, data) in (first_page_no..).zip(data) {
+            self.write_page(page_no, data)?;
+        }
+        Ok(())
+    }
+
+    /// Makes every write so far durable
+    fn sync(&self) -> std::io::Result<()>;
+
+    /// The number of pages in the storage
+    fn len(&self) -> std::io::Result<u64>;
+
+    /// Whether the storage has no pages at all
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Shrinks the storage to `n_pages`. Pages past it are lost.
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()>;
+
+    /// Makes room for at least `n_pages`, so writing them doesn't need to grow the storage. New
+    /// pages are zeroed.
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()>;
+
+    /// The path of the file backing the storage, if there is one
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The most pages the storage can hold, if it is limited
+    fn max_pages(&self) -> Option<u64> {
+        None
+    }
+
+    /// The file descriptor to submit IO to the kernel directly, if there is one
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Implements the trait for a smart pointer by delegating to the backend it points to
+macro_rules! impl_storage_backend_for_pointer {
+    ($pointer:ident) => {
+        impl<B: StorageBackend + ?Sized> StorageBackend for $pointer<B> {
+            fn page_size(&self) -> usize {
+                self.as_ref().page_size()
+            }
+
+            fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+                self.as_ref().read_page(page_no, buffer)
+            }
+
+            fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+                self.as_ref().write_page(page_no, data)
+            }
+
+            fn read_pages(
+                &self,
+                first_page_no: PageNo,
+                buffers: &mut [&mut [u8]],
+            ) -> std::io::Result<()> {
+                self.as_ref().read_pages(first_page_no, buffers)
+            }
+
+            fn write_pages(&self, first_page_no: PageNo, data: &[&[u8]]) -> std::io::Result<()> {
+                self.as_ref().write_pages(first_page_no, data)
+            }
+
+            fn sync(&self) -> std::io::Result<()> {
+                self.as_ref().sync()
+            }
+
+            fn len(&self) -> std::io::Result<u64> {
+                self.as_ref().len()
+            }
+
+            fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+                self.as_ref().truncate(n_pages)
+            }
+
+            fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+                self.as_ref().allocate(n_pages)
+            }
+
+            fn path(&self) -> Option<&Path> {
+                self.as_ref().path()
+            }
+
+            fn max_pages(&self) -> Option<u64> {
+                self.as_ref().max_pages()
+            }
+
+            fn raw_fd(&self) -> Option<RawFd> {
+                self.as_ref().raw_fd()
+            }
+        }
+    };
+}
+
+// Sharing a backend lets tests open it again after the database using it is gone, and boxing it
+// lets the database pick the backend at runtime
+impl_storage_backend_for_pointer!(Arc);
+impl_storage_backend_for_pointer!(Box);
+
+/// Pages stored in a regular file, read and written with positional IO
+pub struct FileBackend {
+    file: File,
+    path: PathBuf,
+    read_only: bool,
+    page_size: usize,
+}
+
+impl FileBackend {
+    /// Opens the database file, or creates it if it doesn't exist. Existing data is preserved.
+    /// The file is locked until the backend is dropped, opening it again fails with `WouldBlock`
+    /// until then, even from another process.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        FileBackend::from_file(file, path.as_ref(), false)
+    }
+
+    /// Opens an existing database file without write permission. Several backends can have the
+    /// file open read-only at the same time, but opening it with `open` fails meanwhile.
+    pub fn open_read_only(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path.as_ref())?;
+        FileBackend::from_file(file, path.as_ref(), true)
+    }
+
+    /// Creates a new database file. Fails if the file already exists.
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())?;
+        FileBackend::from_file(file, path.as_ref(), false)
+    }
+
+    fn from_file(file: File, path: &Path, read_only: bool) -> std::io::Result<Self> {
+        // An advisory lock (flock), so two buffer pools never write the same file
+        let lock = if read_only {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        lock.map_err(|err| match err {
+            TryLockError::WouldBlock => std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                format!("{path:?} is locked by another process"),
+            ),
+            TryLockError::Error(err) => err,
+        })?;
+        Ok(FileBackend {
+            file,
+            path: path.to_path_buf(),
+            read_only,
+            page_size: PAGE_SIZE,
+        })
+    }
+
+    /// Reads and writes pages of `page_size` bytes instead of `PAGE_SIZE`
+    pub fn with_page_size(self, page_size: usize) -> Self {
+        FileBackend { page_size, ..self }
+    }
+
+    pub(super) fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub(super) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The size of the file in bytes, which may not be a whole number of pages
+    pub(super) fn byte_len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.file
+            .read_exact_at(buffer, page_no_to_file_offset(page_no, self.page_size))
+    }
+
+    fn write_page(&self, page_no: PageNo, data: &[u8]) -> std::io::Result<()> {
+        self.file
+            .write_all_at(data, page_no_to_file_offset(page_no, self.page_size))
+    }
+
+    fn read_pages(&self, first_page_no: PageNo, buffers: &mut [&mut [u8]]) -> std::io::Result<()> {
+        let mut slices: Vec<_> = buffers.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+        let mut slices = &mut slices[..];
+        let mut offset = page_no_to_file_offset(first_page_no, self.page_size);
+        while !slices.is_empty() {
+            let n_buffers = slices.len().min(MAX_IO_VECTORS);
+            // SAFETY: IoSliceMut is ABI compatible with iovec and the buffers outlive the call
+            let n_read = unsafe {
+                libc::preadv(
+                    self.file.as_raw_fd(),
+                    slices.as_ptr() as *const libc::iovec,
+                    n_buffers as libc::c_int,
+                    offset as libc::off_t,
+                )
+            };
+            match n_read {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n if n < 0 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                n => {
+                    offset += n as u64;
+                    IoSliceMut::advance_slices(&mut slices, n as usize);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pages(&self, first_page_no: PageNo, data: &[&[u8]]) -> std::io::Result<()> {
+        let mut slices: Vec<_> = data.iter().map(|d| IoSlice::new(d)).collect();
+        let mut slices = &mut slices[..];
+        let mut offset = page_no_to_file_offset(first_page_no, self.page_size);
+        while !slices.is_empty() {
+            let n_buffers = slices.len().min(MAX_IO_VECTORS);
+            // SAFETY: IoSlice is ABI compatible with iovec and the buffers outlive the call
+            let n_written = unsafe {
+                libc::pwritev(
+                    self.file.as_raw_fd(),
+                    slices.as_ptr() as *const libc::iovec,
+                    n_buffers as libc::c_int,
+                    offset as libc::off_t,
+                )
+            };
+            match n_written {
+                0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                n if n < 0 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                n => {
+                    offset += n as u64;
+                    IoSlice::advance_slices(&mut slices, n as usize);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.byte_len()? / self.page_size as u64)
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        self.file.set_len(n_pages * self.page_size as u64)
+    }
+
+    /// Reserves the new pages with fallocate, so the filesystem can place them contiguously and
+    /// a full disk is reported now instead of on a later write
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        let len = self.len()?;
+        if len >= n_pages {
+            return Ok(());
+        }
+        let offset = len * self.page_size as u64;
+        let n_bytes = (n_pages - len) * self.page_size as u64;
+        loop {
+            // SAFETY: plain syscall on a file we own
+            let result = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    0,
+                    offset as libc::off_t,
+                    n_bytes as libc::off_t,
+                )
+            };
+            if result == 0 {
+                return Ok(());
+            }
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // The filesystem can't preallocate, growing the file is the best we can do
+                Some(libc::EOPNOTSUPP) => {
+                    return self.file.set_len(n_pages * self.page_size as u64);
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+}
+
+/// Pages stored in a vector. Nothing survives the process, which is what tests and temporary
+/// databases want.
+pub struct MemoryBackend {
+    data: RwLock<Vec<u8>>,
+    /// Growing past this many pages fails with `OutOfMemory`
+    max_pages: Option<u64>,
+    page_size: usize,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::from(Vec::new())
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    /// Holds pages of `page_size` bytes instead of `PAGE_SIZE`
+    pub fn with_page_size(self, page_size: usize) -> Self {
+        MemoryBackend { page_size, ..self }
+    }
+
+    /// A backend that holds at most `max_pages` pages
+    pub fn with_max_pages(max_pages: u64) -> Self {
+        MemoryBackend {
+            max_pages: Some(max_pages),
+            ..MemoryBackend::default()
+        }
+    }
+
+    fn check_limit(&self, n_pages: u64) -> std::io::Result<()> {
+        match self.max_pages {
+            Some(max_pages) if n_pages > max_pages => Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                format!("Memory storage is limited to {max_pages} pages"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<Vec<u8>> for MemoryBackend {
+    fn from(data: Vec<u8>) -> Self {
+        MemoryBackend {
+            data: RwLock::new(data),
+            max_pages: None,
+            page_size: PAGE_SIZE,
+        }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&self, page_no: PageNo, buffer: &mut [u8]) -> std::io::Result<()> {
+        let data = self.data.read().unwrap_or_else(PoisonError::into_inner);
+        let offset = page_no_to_file_offset(page_no, self.page_size) as usize;
+        let page = data
+            .get(offset..offset + buffer.len())
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(page);
+        Ok(())
+    }
+
+    fn write_page(&self, page_no: PageNo, page: &[u8]) -> std::io::Result<()> {
+        self.check_limit(page_no as u64 + 1)?;
+        let mut data = self.data.write().unwrap_or_else(PoisonError::into_inner);
+        let offset = page_no_to_file_offset(page_no, self.page_size) as usize;
+        if data.len() < offset + page.len() {
+            data.resize(offset + page.len(), 0);
+        }
+        data[offset..offset + page.len()].copy_from_slice(page);
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        let data = self.data.read().unwrap_or_else(PoisonError::into_inner);
+        Ok((data.len() / self.page_size) as u64)
+    }
+
+    fn truncate(&self, n_pages: u64) -> std::io::Result<()> {
+        let mut data = self.data.write().unwrap_or_else(PoisonError::into_inner);
+        data.truncate(n_pages as usize * self.page_size);
+        Ok(())
+    }
+
+    fn allocate(&self, n_pages: u64) -> std::io::Result<()> {
+        self.check_limit(n_pages)?;
+        let mut data = self.data.write().unwrap_or_else(PoisonError::into_inner);
+        let len = n_pages as usize * self.page_size;
+        if data.len() < len {
+            data.resize(len, 0);
+        }
+        Ok(())
+    }
+
+    fn max_pages(&self) -> Option<u64> {
+        self.max_pages
+    }
+}
+
+/* Utils */
+
+pub(crate) fn page_no_to_file_offset(page_no: PageNo, page_size: usize) -> u64 {
+    page_no as u64 * page_size as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::logger::setup_logger;
+    use crate::storage::MmapBackend;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("maridbel-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// The behavior every backend must have
+    fn check_backend(backend: &impl StorageBackend) {
+        assert!(backend.is_empty().unwrap());
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        assert_eq!(
+            backend.read_page(0, &mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        // Writing past the end grows the storage
+        backend.write_page(2, &[2u8; PAGE_SIZE]).unwrap();
+        assert_eq!(backend.len().unwrap(), 3);
+        backend.read_page(0, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        backend
+            .write_pages(0, &[&[7u8; PAGE_SIZE], &[8u8; PAGE_SIZE]])
+            .unwrap();
+        let (mut first, mut second) = (vec![0u8; PAGE_SIZE], vec![0u8; PAGE_SIZE]);
+        backend
+            .read_pages(1, &mut [&mut first, &mut second])
+            .unwrap();
+        assert!(first.iter().all(|b| *b == 8));
+        assert!(second.iter().all(|b| *b == 2));
+
+        backend.allocate(8).unwrap();
+        assert_eq!(backend.len().unwrap(), 8);
+        backend.allocate(4).unwrap();
+        assert_eq!(backend.len().unwrap(), 8);
+
+        backend.truncate(1).unwrap();
+        assert_eq!(backend.len().unwrap(), 1);
+        backend.read_page(0, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 7));
+        assert!(backend.read_page(1, &mut buffer).is_err());
+
+        backend.sync().unwrap();
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        setup_logger();
+        check_backend(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn test_file_backend() {
+        setup_logger();
+        let path = temp_path("file-backend");
+        check_backend(&FileBackend::create(&path).unwrap());
+
+        let backend = FileBackend::open(&path).unwrap();
+        assert_eq!(backend.path(), Some(path.as_path()));
+        assert_eq!(backend.len().unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_backend() {
+        setup_logger();
+        let path = temp_path("mmap-backend");
+        check_backend(&MmapBackend::create(&path).unwrap());
+
+        // The data written through the mapping is in the file
+        let backend = FileBackend::open(&path).unwrap();
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        backend.read_page(0, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 7));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use super::layout::{
+    check_page_type, read_u16, read_u64, reset_page, write_u16, write_u64, PageData, PageDataMut,
+    PageId, PageType, INVALID_PAGE_ID,
+};
+use super::links::PageLink;
+use crate::errors::PageError;
+
+/// page type (8bit) + reserved (8bit) + number of keys (16bit) + reserved (32bit) +
+/// leftmost child (64bit)
+const BTREE_INTERNAL_HEADER_SIZE: usize = 16;
+/// key (64bit) + child page id (64bit)
+const BTREE_INTERNAL_ENTRY_SIZE: usize = 16;
+/// page type (8bit) + reserved (8bit) + number of keys (16bit) + reserved (32bit) +
+/// next leaf (64bit)
+const BTREE_LEAF_HEADER_SIZE: usize = 16;
+/// key (64bit) + value (64bit)
+const BTREE_LEAF_ENTRY_SIZE: usize = 16;
+
+const BTREE_LEN_OFFSET: usize = 2;
+const BTREE_LINK_OFFSET: usize = 8;
+
+/// Entries are read at offsets computed from the stored length, which must not run past the page
+fn check_len(len: usize, capacity: usize) -> Result<(), PageError> {
+    if len > capacity {
+        return Err(PageError::CorruptedPage(format!(
+            "{len} entries exceed the page capacity of {capacity}"
+        )));
+    }
+    Ok(())
+}
+
+/// An inner node of a B+tree. It stores `len` keys and `len + 1` children: the child at the
+/// left of the first key lives in the header and the others are stored next to their keys.
+pub struct BTreeInternalPage<D> {
+    data: D,
+}
+
+/// A leaf node of a B+tree. Leaves are linked to their right sibling to allow range scans.
+pub struct BTreeLeafPage<D> {
+    data: D,
+}
+
+impl<D: PageData> BTreeInternalPage<D> {
+    /// Interprets the page as a B+tree inner node. Fails if the page is tagged with another type or
+    /// holds more entries than fit in it.
+    pub fn new(data: D) -> Result<Self, PageError> {
+        check_page_type(&data, PageType::BTreeInternal)?;
+        let page = BTreeInternalPage { data };
+        check_len(page.len(), page.capacity())?;
+        Ok(page)
+    }
+
+    pub fn len(&self) -> usize {
+        read_u16(&self.data, BTREE_LEN_OFFSET) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of keys the page can hold
+    pub fn capacity(&self) -> usize {
+        (self.data.bytes().len() - BTREE_INTERNAL_HEADER_SIZE) / BTREE_INTERNAL_ENTRY_SIZE
+    }
+
+    pub fn key_at(&self, n: usize) -> u64 {
+        read_u64(&self.data, self.entry_offset(n))
+    }
+
+    /// The child at the left of all keys
+    pub fn leftmost_child(&self) -> PageId {
+        read_u64(&self.data, BTREE_LINK_OFFSET)
+    }
+
+    /// The child at the right of the nth key
+    pub fn child_at(&self, n: usize) -> PageId {
+        read_u64(&self.data, self.entry_offset(n) + 8)
+    }
+
+    /// Every child of the node
+    pub(crate) fn links(&self) -> Vec<PageLink> {
+        let mut links = Vec::with_capacity(self.len() + 1);
+        if self.leftmost_child() != INVALID_PAGE_ID {
+            links.push(PageLink::new(BTREE_LINK_OFFSET, self.leftmost_child()));
+        }
+        for n in 0..self.len() {
+            links.push(PageLink::new(self.entry_offset(n) + 8, self.child_at(n)));
+        }
+        links
+    }
+
+    fn entry_offset(&self, n: usize) -> usize {
+        assert!(n < self.capacity(), "Entry {n} out of bounds");
+        BTREE_INTERNAL_HEADER_SIZE + n * BTREE_INTERNAL_ENTRY_SIZE
+    }
+}
+
+impl<D: PageDataMut> BTreeInternalPage<D> {
+    /// Formats the page as an empty B+tree inner node
+    pub fn init(mut data: D) -> Self {
+        reset_page(&mut data, PageType::BTreeInternal);
+        write_u64(&mut data, BTREE_LINK_OFFSET, INVALID_PAGE_ID);
+        BTreeInternalPage { data }
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        assert!(
+            len <= self.capacity(),
+            "Length {len} exceeds the page capacity"
+        );
+        write_u16(&mut self.data, BTREE_LEN_OFFSET, len as u16);
+    }
+
+    pub fn set_leftmost_child(&mut self, child: PageId) {
+        write_u64(&mut self.data, BTREE_LINK_OFFSET, child);
+    }
+
+    pub fn set_entry(&mut self, n: usize, key: u64, child: PageId) {
+        let offset = self.entry_offset(n);
+        write_u64(&mut self.data, offset, key);
+        write_u64(&mut self.data, offset + 8, child);
+    }
+}
+
+impl<D: PageData> BTreeLeafPage<D> {
+    /// Interprets the page as a B+tree leaf. Fails if the page is tagged with another type or
+    /// holds more entries than fit in it.
+    pub fn new(data: D) -> Result<Self, PageError> {
+        check_page_type(&data, PageType::BTreeLeaf)?;
+        let page = BTreeLeafPage { data };
+        check_len(page.len(), page.capacity())?;
+        Ok(page)
+    }
+
+    pub fn len(&self) -> usize {
+        read_u16(&self.data, BTREE_LEN_OFFSET) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of entries the page can hold
+    pub fn capacity(&self) -> usize {
+        (self.data.bytes().len() - BTREE_LEAF_HEADER_SIZE) / BTREE_LEAF_ENTRY_SIZE
+    }
+
+    pub fn key_at(&self, n: usize) -> u64 {
+        read_u64(&self.data, self.entry_offset(n))
+    }
+
+    pub fn value_at(&self, n: usize) -> u64 {
+        read_u64(&self.data, self.entry_offset(n) + 8)
+    }
+
+    /// The right sibling of this leaf, or INVALID_PAGE_ID if it is the last one
+    pub fn next_leaf(&self) -> PageId {
+        read_u64(&self.data, BTREE_LINK_OFFSET)
+    }
+
+    /// The right sibling of the leaf. Values are not page ids.
+    pub(crate) fn links(&self) -> Vec<PageLink> {
+        match self.next_leaf() {
+            INVALID_PAGE_ID => Vec::new(),
+            next => vec![PageLink::new(BTREE_LINK_OFFSET, next)],
+        }
+    }
+
+    fn entry_offset(&self, n: usize) -> usize {
+        assert!(n < self.capacity(), "Entry {n} out of bounds");
+        BTREE_LEAF_HEADER_SIZE + n * BTREE_LEAF_ENTRY_SIZE
+    }
+}
+
+impl<D: PageDataMut> BTreeLeafPage<D> {
+    /// Formats the page as an empty B+tree leaf
+    pub fn init(mut data: D) -> Self {
+        reset_page(&mut data, PageType::BTreeLeaf);
+        write_u64(&mut data, BTREE_LINK_OFFSET, INVALID_PAGE_ID);
+        BTreeLeafPage { data }
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        assert!(
+            len <= self.capacity(),
+            "Length {len} exceeds the page capacity"
+        );
+        write_u16(&mut self.data, BTREE_LEN_OFFSET, len as u16);
+    }
+
+    pub fn set_next_leaf(&mut self, next: PageId) {
+        write_u64(&mut self.data, BTREE_LINK_OFFSET, next);
+    }
+
+    pub fn set_entry(&mut self, n: usize, key: u64, value: u64) {
+        let offset = self.entry_offset(n);
+        write_u64(&mut self.data, offset, key);
+        write_u64(&mut self.data, offset + 8, value);
+    }
+}
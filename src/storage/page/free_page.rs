@@ -0,0 +1,54 @@
+use super::layout::{
+    check_page_type, read_u32, read_u64, reset_page, write_u32, write_u64, PageData, PageDataMut,
+    PageId, PageNo, PageType,
+};
+use crate::errors::PageError;
+
+/// Tells a free page apart from a page that was never formatted, which has the same tag
+pub const FREE_PAGE_MAGIC: &[u8; 8] = b"FREEPAGE";
+
+/// page type (8bit) + reserved (56bit)
+const FREE_PAGE_MAGIC_OFFSET: usize = 8;
+const FREE_PAGE_ID_OFFSET: usize = 16;
+const FREE_PAGE_NEXT_OFFSET: usize = 24;
+
+/// A page given back with `BufferPool::free_page`. The free pages of a data file are chained
+/// from its header. A free page records its own id, so a copy of it found anywhere else is not
+/// taken for a free page.
+pub struct FreePage<D> {
+    data: D,
+}
+
+impl<D: PageData> FreePage<D> {
+    /// Interprets the page as the free page with the given id. Fails if the page is tagged with
+    /// another type or was not freed as this page.
+    pub fn new(data: D, page_id: PageId) -> Result<Self, PageError> {
+        check_page_type(&data, PageType::Free)?;
+        let page = FreePage { data };
+        let magic = &page.data.bytes()[FREE_PAGE_MAGIC_OFFSET..FREE_PAGE_MAGIC_OFFSET + 8];
+        if magic != FREE_PAGE_MAGIC || read_u64(&page.data, FREE_PAGE_ID_OFFSET) != page_id {
+            return Err(PageError::CorruptedPage(format!(
+                "page {page_id} is not a free page"
+            )));
+        }
+        Ok(page)
+    }
+
+    /// The next free page of the data file, or 0 if this is the last one. The header, page 0, is
+    /// never free.
+    pub fn next(&self) -> PageNo {
+        read_u32(&self.data, FREE_PAGE_NEXT_OFFSET)
+    }
+}
+
+impl<D: PageDataMut> FreePage<D> {
+    /// Formats the page as the free page with the given id, followed by `next` in the free list
+    pub fn init(mut data: D, page_id: PageId, next: PageNo) -> Self {
+        reset_page(&mut data, PageType::Free);
+        data.bytes_mut(FREE_PAGE_MAGIC_OFFSET..FREE_PAGE_MAGIC_OFFSET + 8)
+            .copy_from_slice(FREE_PAGE_MAGIC);
+        write_u64(&mut data, FREE_PAGE_ID_OFFSET, page_id);
+        write_u32(&mut data, FREE_PAGE_NEXT_OFFSET, next);
+        FreePage { data }
+    }
+}
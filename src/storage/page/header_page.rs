@@ -0,0 +1,223 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use super::layout::{
+    check_page_type, read_u16, read_u32, read_u64, reset_page, write_u16, write_u32, write_u64,
+    FileId, PageData, PageDataMut, PageId, PageNo, PageType, INVALID_PAGE_ID,
+};
+use super::links::PageLink;
+use crate::errors::PageError;
+
+/// Identifies a maridbel data file
+pub const DATABASE_MAGIC: &[u8; 8] = b"MARIDBEL";
+/// Bumped every time the on-disk format changes in an incompatible way
+pub const DATABASE_FORMAT_VERSION: u32 = 4;
+
+/// page type (8bit) + reserved (56bit)
+const HEADER_MAGIC_OFFSET: usize = 8;
+const HEADER_FORMAT_VERSION_OFFSET: usize = 16;
+const HEADER_PAGE_SIZE_OFFSET: usize = 20;
+const HEADER_FILE_ID_OFFSET: usize = 24;
+const HEADER_N_PAGES_OFFSET: usize = 28;
+const HEADER_FREE_LIST_OFFSET: usize = 32;
+const HEADER_N_FREE_PAGES_OFFSET: usize = 36;
+const HEADER_CATALOG_ROOT_OFFSET: usize = 40;
+const HEADER_N_TABLESPACES_OFFSET: usize = 60;
+/// The fixed fields end here, the rest of the page lists the tablespaces
+const HEADER_TABLESPACES_OFFSET: usize = 64;
+
+/// file id (32bit) + name length (16bit) + path length (16bit), followed by the name and the path
+const TABLESPACE_ENTRY_SIZE: usize = 8;
+
+/// The first page of every data file. It describes how the rest of the file must be read, and
+/// the header of the database file (file 0) also lists the tablespaces stored in other files.
+pub struct HeaderPage<D> {
+    data: D,
+}
+
+/// A tablespace recorded in the header of the database file, opened with the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TablespaceEntry {
+    pub name: String,
+    pub file_id: FileId,
+    pub path: PathBuf,
+}
+
+impl<D: PageData> HeaderPage<D> {
+    /// Interprets the page as a data file header. Fails if the page is tagged with another type.
+    pub fn new(data: D) -> Result<Self, PageError> {
+        check_page_type(&data, PageType::Header)?;
+        Ok(HeaderPage { data })
+    }
+
+    pub fn has_valid_magic(&self) -> bool {
+        &self.data.bytes()[HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET + 8] == DATABASE_MAGIC
+    }
+
+    pub fn format_version(&self) -> u32 {
+        read_u32(&self.data, HEADER_FORMAT_VERSION_OFFSET)
+    }
+
+    /// The page size the database was created with
+    pub fn page_size(&self) -> usize {
+        read_u32(&self.data, HEADER_PAGE_SIZE_OFFSET) as usize
+    }
+
+    /// The id the pages of the file are addressed with. The database file has the id 0.
+    pub fn file_id(&self) -> FileId {
+        read_u32(&self.data, HEADER_FILE_ID_OFFSET)
+    }
+
+    /// The number of pages handed out by `BufferPool::allocate_page`, including the header. The
+    /// next page is allocated past them.
+    pub fn n_pages(&self) -> u32 {
+        read_u32(&self.data, HEADER_N_PAGES_OFFSET)
+    }
+
+    /// The first page of the free list of the file, or 0 if it is empty. The next ones are
+    /// linked from it, see `FreePage`.
+    pub fn free_list_head(&self) -> PageNo {
+        read_u32(&self.data, HEADER_FREE_LIST_OFFSET)
+    }
+
+    /// The number of pages in the free list
+    pub fn n_free_pages(&self) -> u32 {
+        read_u32(&self.data, HEADER_N_FREE_PAGES_OFFSET)
+    }
+
+    /// The root page of the catalog, or INVALID_PAGE_ID if there is none. Only the header of the
+    /// database file records one.
+    pub fn catalog_root(&self) -> PageId {
+        read_u64(&self.data, HEADER_CATALOG_ROOT_OFFSET)
+    }
+
+    /// The catalog root is the only page id stored in the header
+    pub(crate) fn links(&self) -> Vec<PageLink> {
+        match self.catalog_root() {
+            INVALID_PAGE_ID => Vec::new(),
+            root => vec![PageLink::new(HEADER_CATALOG_ROOT_OFFSET, root)],
+        }
+    }
+
+    /// The tablespaces recorded with `add_tablespace`, in the order they were added
+    pub fn tablespaces(&self) -> Result<Vec<TablespaceEntry>, PageError> {
+        let n_tablespaces = read_u32(&self.data, HEADER_N_TABLESPACES_OFFSET) as usize;
+        let mut tablespaces = Vec::with_capacity(n_tablespaces.min(self.data.bytes().len()));
+        let mut offset = HEADER_TABLESPACES_OFFSET;
+        for _ in 0..n_tablespaces {
+            let entry = self.read_tablespace(offset).ok_or_else(|| {
+                PageError::CorruptedPage(format!(
+                    "tablespace {} of {n_tablespaces} doesn't fit in the header",
+                    tablespaces.len()
+                ))
+            })?;
+            offset += entry_size(&entry);
+            tablespaces.push(entry);
+        }
+        Ok(tablespaces)
+    }
+
+    /// The tablespace entry at `offset`. None if it runs past the end of the page or its name is
+    /// not UTF-8.
+    fn read_tablespace(&self, offset: usize) -> Option<TablespaceEntry> {
+        let bytes = self.data.bytes();
+        if offset + TABLESPACE_ENTRY_SIZE > bytes.len() {
+            return None;
+        }
+        let file_id = read_u32(&self.data, offset);
+        let name_len = read_u16(&self.data, offset + 4) as usize;
+        let path_len = read_u16(&self.data, offset + 6) as usize;
+        let name_start = offset + TABLESPACE_ENTRY_SIZE;
+        let path_start = name_start + name_len;
+        let name = bytes.get(name_start..path_start)?;
+        let path = bytes.get(path_start..path_start + path_len)?;
+        Some(TablespaceEntry {
+            name: String::from_utf8(name.to_vec()).ok()?,
+            file_id,
+            path: PathBuf::from(OsStr::from_bytes(path)),
+        })
+    }
+
+    /// Where the next tablespace entry goes
+    fn tablespaces_end(&self) -> Result<usize, PageError> {
+        Ok(self
+            .tablespaces()?
+            .iter()
+            .fold(HEADER_TABLESPACES_OFFSET, |end, entry| {
+                end + entry_size(entry)
+            }))
+    }
+}
+
+impl<D: PageDataMut> HeaderPage<D> {
+    /// Formats the page as the header of a fresh data file whose pages are addressed with the
+    /// given file id
+    pub fn init(mut data: D, file_id: FileId) -> Self {
+        let page_size = data.bytes().len();
+        reset_page(&mut data, PageType::Header);
+        data.bytes_mut(HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET + 8)
+            .copy_from_slice(DATABASE_MAGIC);
+        write_u32(
+            &mut data,
+            HEADER_FORMAT_VERSION_OFFSET,
+            DATABASE_FORMAT_VERSION,
+        );
+        write_u32(&mut data, HEADER_PAGE_SIZE_OFFSET, page_size as u32);
+        write_u32(&mut data, HEADER_FILE_ID_OFFSET, file_id);
+        write_u32(&mut data, HEADER_N_PAGES_OFFSET, 1);
+        write_u64(&mut data, HEADER_CATALOG_ROOT_OFFSET, INVALID_PAGE_ID);
+        HeaderPage { data }
+    }
+
+    pub fn set_n_pages(&mut self, n_pages: u32) {
+        write_u32(&mut self.data, HEADER_N_PAGES_OFFSET, n_pages);
+    }
+
+    pub fn set_free_list(&mut self, head: PageNo, n_free_pages: u32) {
+        write_u32(&mut self.data, HEADER_FREE_LIST_OFFSET, head);
+        write_u32(&mut self.data, HEADER_N_FREE_PAGES_OFFSET, n_free_pages);
+    }
+
+    pub fn set_catalog_root(&mut self, root: PageId) {
+        write_u64(&mut self.data, HEADER_CATALOG_ROOT_OFFSET, root);
+    }
+
+    /// Records a tablespace at the end of the list. Returns false if the header has no room left
+    /// for it.
+    pub fn add_tablespace(&mut self, entry: &TablespaceEntry) -> Result<bool, PageError> {
+        let offset = self.tablespaces_end()?;
+        let name = entry.name.as_bytes();
+        let path = entry.path.as_os_str().as_bytes();
+        let (Ok(name_len), Ok(path_len)) = (u16::try_from(name.len()), u16::try_from(path.len()))
+        else {
+            return Ok(false);
+        };
+        if offset + entry_size(entry) > self.data.bytes().len() {
+            return Ok(false);
+        }
+
+        write_u32(&mut self.data, offset, entry.file_id);
+        write_u16(&mut self.data, offset + 4, name_len);
+        write_u16(&mut self.data, offset + 6, path_len);
+        let name_start = offset + TABLESPACE_ENTRY_SIZE;
+        let path_start = name_start + name.len();
+        self.data
+            .bytes_mut(name_start..path_start)
+            .copy_from_slice(name);
+        self.data
+            .bytes_mut(path_start..path_start + path.len())
+            .copy_from_slice(path);
+        let n_tablespaces = read_u32(&self.data, HEADER_N_TABLESPACES_OFFSET);
+        write_u32(
+            &mut self.data,
+            HEADER_N_TABLESPACES_OFFSET,
+            n_tablespaces + 1,
+        );
+        Ok(true)
+    }
+}
+
+fn entry_size(entry: &TablespaceEntry) -> usize {
+    TABLESPACE_ENTRY_SIZE + entry.name.len() + entry.path.as_os_str().len()
+}
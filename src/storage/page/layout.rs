@@ -0,0 +1,187 @@
+use std::ops::Range;
+use std::sync::RwLockReadGuard;
+
+use crate::errors::PageError;
+use crate::storage::buffer::frame::{Frame, FrameWriteLatch};
+
+/// The address of a page: the file it lives in, in the high 32 bits, and its number in that
+/// file, in the low 32 bits. Pages of the default tablespace (file 0) have the same id as their
+/// page number.
+pub type PageId = u64;
+
+/// Identifies a data file. Every tablespace is stored in its own file.
+pub type FileId = u32;
+
+/// The position of a page in its data file. It also represents the offset in the file.
+pub type PageNo = u32;
+
+/// A page id split in its two parts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PageAddress {
+    pub file_id: FileId,
+    pub page_no: PageNo,
+}
+
+impl PageAddress {
+    pub const fn new(file_id: FileId, page_no: PageNo) -> Self {
+        PageAddress { file_id, page_no }
+    }
+
+    pub const fn page_id(self) -> PageId {
+        (self.file_id as PageId) << 32 | self.page_no as PageId
+    }
+
+    pub const fn of(page_id: PageId) -> Self {
+        PageAddress {
+            file_id: (page_id >> 32) as FileId,
+            page_no: page_id as PageNo,
+        }
+    }
+}
+
+impl From<PageId> for PageAddress {
+    fn from(page_id: PageId) -> Self {
+        PageAddress::of(page_id)
+    }
+}
+
+impl From<PageAddress> for PageId {
+    fn from(address: PageAddress) -> Self {
+        address.page_id()
+    }
+}
+
+/// Used in page links (sibling pointers, child pointers) to represent the absence of a page
+pub const INVALID_PAGE_ID: PageId = PageId::MAX;
+
+/// The first byte of every page tells how the rest of the page must be interpreted.
+/// A zeroed page, as the ones we get when the file grows, is tagged as free, but only the pages
+/// formatted as a `FreePage` are in the free list of their data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PageType {
+    Free = 0,
+    Header = 1,
+    Slotted = 2,
+    BTreeInternal = 3,
+    BTreeLeaf = 4,
+}
+
+const PAGE_TYPE_OFFSET: usize = 0;
+
+impl PageType {
+    pub fn from_tag(tag: u8) -> Option<PageType> {
+        match tag {
+            0 => Some(PageType::Free),
+            1 => Some(PageType::Header),
+            2 => Some(PageType::Slotted),
+            3 => Some(PageType::BTreeInternal),
+            4 => Some(PageType::BTreeLeaf),
+            _ => None,
+        }
+    }
+}
+
+/// Read access to the raw bytes of a page. Page views are generic over it so the same page
+/// format can be read from a latched frame or from any other buffer.
+pub trait PageData {
+    fn bytes(&self) -> &[u8];
+}
+
+/// Write access to the raw bytes of a page. Writes are always done over explicit ranges so the
+/// buffer pool knows exactly which bytes were modified.
+pub trait PageDataMut: PageData {
+    fn bytes_mut(&mut self, range: Range<usize>) -> &mut [u8];
+}
+
+impl PageData for &[u8] {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PageData for &mut [u8] {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PageDataMut for &mut [u8] {
+    fn bytes_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        &mut self[range]
+    }
+}
+
+impl PageData for RwLockReadGuard<'_, Frame> {
+    fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl PageData for FrameWriteLatch<'_> {
+    fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl PageDataMut for FrameWriteLatch<'_> {
+    fn bytes_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        self.range_mut(range)
+    }
+}
+
+/// Returns the type the page is tagged with
+pub fn page_type(data: &impl PageData) -> Result<PageType, PageError> {
+    let tag = data.bytes()[PAGE_TYPE_OFFSET];
+    PageType::from_tag(tag).ok_or(PageError::UnknownPageType(tag))
+}
+
+pub(crate) fn check_page_type(data: &impl PageData, expected: PageType) -> Result<(), PageError> {
+    let found = data.bytes()[PAGE_TYPE_OFFSET];
+    if found == expected as u8 {
+        Ok(())
+    } else {
+        Err(PageError::PageTypeMismatch { expected, found })
+    }
+}
+
+/// Zeroes the page and tags it with the given type
+pub(crate) fn reset_page(data: &mut impl PageDataMut, page_type: PageType) {
+    let len = data.bytes().len();
+    data.bytes_mut(0..len).fill(0);
+    data.bytes_mut(PAGE_TYPE_OFFSET..PAGE_TYPE_OFFSET + 1)[0] = page_type as u8;
+}
+
+/* Utils. Every integer in a page is stored in big endian */
+
+pub(crate) fn read_u16(data: &impl PageData, offset: usize) -> u16 {
+    let bytes = &data.bytes()[offset..offset + 2];
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+pub(crate) fn read_u32(data: &impl PageData, offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data.bytes()[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+pub(crate) fn read_u64(data: &impl PageData, offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data.bytes()[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+pub(crate) fn write_u16(data: &mut impl PageDataMut, offset: usize, value: u16) {
+    data.bytes_mut(offset..offset + 2)
+        .copy_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn write_u32(data: &mut impl PageDataMut, offset: usize, value: u32) {
+    data.bytes_mut(offset..offset + 4)
+        .copy_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn write_u64(data: &mut impl PageDataMut, offset: usize, value: u64) {
+    data.bytes_mut(offset..offset + 8)
+        .copy_from_slice(&value.to_be_bytes());
+}
@@ -0,0 +1,37 @@
+use super::btree_page::{BTreeInternalPage, BTreeLeafPage};
+use super::header_page::HeaderPage;
+use super::layout::{page_type, write_u64, PageData, PageDataMut, PageId, PageType};
+use crate::errors::PageError;
+
+/// A page id stored in a page, pointing to another page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageLink {
+    /// Where the page id is stored in the page
+    offset: usize,
+    pub page_id: PageId,
+}
+
+impl PageLink {
+    pub(crate) fn new(offset: usize, page_id: PageId) -> Self {
+        PageLink { offset, page_id }
+    }
+}
+
+/// The links from the page to other pages, by the type of the page. A page can only be moved
+/// if every link to it is rewritten, so every page type storing page ids must list them here.
+/// The tuples of slotted pages and the values of B+tree leaves are opaque, they are not links.
+pub fn page_links(data: &impl PageData) -> Result<Vec<PageLink>, PageError> {
+    let data = data.bytes();
+    let links = match page_type(&data)? {
+        PageType::Free | PageType::Slotted => Vec::new(),
+        PageType::Header => HeaderPage::new(data)?.links(),
+        PageType::BTreeInternal => BTreeInternalPage::new(data)?.links(),
+        PageType::BTreeLeaf => BTreeLeafPage::new(data)?.links(),
+    };
+    Ok(links)
+}
+
+/// Points the link, found in the page with `page_links`, to another page
+pub fn set_link(data: &mut impl PageDataMut, link: PageLink, page_id: PageId) {
+    write_u64(data, link.offset, page_id);
+}
@@ -0,0 +1,118 @@
+use super::layout::{
+    check_page_type, read_u16, reset_page, write_u16, PageData, PageDataMut, PageType,
+};
+use crate::errors::PageError;
+use crate::storage::tuple::Tuple;
+
+// TODO
+// - Representing null values
+
+/// 16bit offset + 16bit length
+const SLOTTED_PAGE_SLOT_SIZE: usize = 4;
+/// page type (8bit) + reserved (8bit) + number of slots (16bit) + free space end (16bit)
+const SLOTTED_PAGE_HEADER_SIZE: usize = 6;
+const SLOTTED_PAGE_N_SLOTS_OFFSET: usize = 2;
+const SLOTTED_PAGE_FREE_SPACE_END_OFFSET: usize = 4;
+
+/// The slot array grows from the start of the page and the tuples from the end of it
+pub struct SlottedPage<D> {
+    data: D,
+}
+
+impl<D: PageData> SlottedPage<D> {
+    /// Interprets the page as a slotted page. Fails if the page is tagged with another type or
+    /// its slot array and free space don't fit in the page.
+    pub fn new(data: D) -> Result<Self, PageError> {
+        check_page_type(&data, PageType::Slotted)?;
+        let page = SlottedPage { data };
+        let page_size = page.data.bytes().len();
+        if page.free_space_end() > page_size {
+            return Err(PageError::CorruptedPage(format!(
+                "free space ends at {} past the end of the page",
+                page.free_space_end()
+            )));
+        }
+        if page.slots_end() > page.free_space_end() {
+            return Err(PageError::CorruptedPage(format!(
+                "{} slots overlap the tuples",
+                page.n_slots()
+            )));
+        }
+        Ok(page)
+    }
+
+    pub fn n_slots(&self) -> usize {
+        read_u16(&self.data, SLOTTED_PAGE_N_SLOTS_OFFSET) as usize
+    }
+
+    /// The number of bytes available for a new tuple and its slot
+    pub fn free_space(&self) -> usize {
+        self.free_space_end() - self.slots_end()
+    }
+
+    /// Lookups the slot array for the given slot number. Fails if the slot doesn't exist or
+    /// points outside of the tuples area.
+    pub fn get_n_tuple(&self, n: usize) -> Result<Tuple<'_>, PageError> {
+        if n >= self.n_slots() {
+            return Err(PageError::SlotOutOfBounds(n));
+        }
+        let slot_offset = SLOTTED_PAGE_HEADER_SIZE + n * SLOTTED_PAGE_SLOT_SIZE;
+
+        let offset = read_u16(&self.data, slot_offset) as usize;
+        let length = read_u16(&self.data, slot_offset + 2) as usize;
+        if offset < self.free_space_end() || offset + length > self.data.bytes().len() {
+            return Err(PageError::CorruptedPage(format!(
+                "slot {n} points to {offset}..{} outside of the tuples",
+                offset + length
+            )));
+        }
+
+        Ok(Tuple::from(&self.data.bytes()[offset..offset + length]))
+    }
+
+    fn slots_end(&self) -> usize {
+        SLOTTED_PAGE_HEADER_SIZE + self.n_slots() * SLOTTED_PAGE_SLOT_SIZE
+    }
+
+    fn free_space_end(&self) -> usize {
+        read_u16(&self.data, SLOTTED_PAGE_FREE_SPACE_END_OFFSET) as usize
+    }
+}
+
+impl<D: PageDataMut> SlottedPage<D> {
+    /// Formats the page as an empty slotted page
+    pub fn init(mut data: D) -> Self {
+        let page_size = data.bytes().len();
+        reset_page(&mut data, PageType::Slotted);
+        write_u16(
+            &mut data,
+            SLOTTED_PAGE_FREE_SPACE_END_OFFSET,
+            page_size as u16,
+        );
+        SlottedPage { data }
+    }
+
+    /// Appends a tuple to the page and returns its slot number, or None if it doesn't fit
+    pub fn insert_tuple(&mut self, tuple: &[u8]) -> Option<usize> {
+        if tuple.len() + SLOTTED_PAGE_SLOT_SIZE > self.free_space() {
+            return None;
+        }
+
+        let n = self.n_slots();
+        let offset = self.free_space_end() - tuple.len();
+        let slot_offset = SLOTTED_PAGE_HEADER_SIZE + n * SLOTTED_PAGE_SLOT_SIZE;
+
+        self.data
+            .bytes_mut(offset..offset + tuple.len())
+            .copy_from_slice(tuple);
+        write_u16(&mut self.data, slot_offset, offset as u16);
+        write_u16(&mut self.data, slot_offset + 2, tuple.len() as u16);
+        write_u16(&mut self.data, SLOTTED_PAGE_N_SLOTS_OFFSET, n as u16 + 1);
+        write_u16(
+            &mut self.data,
+            SLOTTED_PAGE_FREE_SPACE_END_OFFSET,
+            offset as u16,
+        );
+        Some(n)
+    }
+}
@@ -9,4 +9,8 @@ impl<'a> Tuple<'a> {
     pub fn from(data: &'a [u8]) -> Self {
         Tuple { data }
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data
+    }
 }